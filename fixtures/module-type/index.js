@@ -0,0 +1,2 @@
+require('esm-pkg')
+require('./plain.cjs')
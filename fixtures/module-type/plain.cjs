@@ -0,0 +1 @@
+module.exports = { export: require('./helper') }
@@ -1,152 +1,512 @@
+use crate::disk_cache::DiskCache;
 use crate::input_options::InputOptions;
-use crate::modules::{Module, ModuleState};
-use crate::resolver::Resolved;
+use crate::manifest::{self, PackageCache};
+use crate::modules::{Module, ModuleInfo, ModuleState};
+use crate::resolver::{real_path, Resolved};
 use crate::source_maps::SourceMapOutput;
-use crate::worker::{Work, WorkDone, Worker, WorkerInit};
-use crate::writer::Writer;
+use crate::worker::{self, Work, WorkDone, WorkQueue, Worker, WorkerInit};
+use crate::writer::{Writer, LIVE_RELOAD_JS};
 use crate::CliError;
-use crossbeam::queue::SegQueue;
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet};
 use matches::debug_assert_matches;
+use std::ffi::OsString;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc;
-use std::sync::Arc;
-use std::thread;
-use std::{fs, io};
+use std::sync::{mpsc, Arc, Mutex};
+use std::{fmt, mem, thread};
+use std::{fs, io, process};
 
+#[cfg(test)]
 pub fn bundle(
     entry_point: &Path,
     input_options: InputOptions,
     output: &str,
     map_output: &SourceMapOutput,
+    cache: &Arc<PackageCache>,
+    jobs: usize,
 ) -> Result<FnvHashMap<PathBuf, Module>, CliError> {
-    let mut pending = 0;
-    let thread_count = num_cpus::get();
-    let (tx, rx) = mpsc::channel();
-    let worker_init = WorkerInit {
-        tx,
+    let mut bundler = Bundler::new(
+        entry_point,
         input_options,
-        quit: Arc::new(AtomicBool::new(false)),
-        queue: Arc::new(SegQueue::new()),
-    };
+        output,
+        map_output,
+        Arc::clone(cache),
+        jobs,
+    );
+    bundler.build(&[])?;
+    let modules = mem::take(&mut bundler.modules);
+    Ok(bundler.relative_to_entry(modules))
+}
+
+// builds a bundle, and rebuilds it in watch mode reusing whatever the changes didn't affect
+#[derive(Debug)]
+pub struct Bundler<'a> {
+    entry_point: PathBuf,
+    input_options: InputOptions,
+    output: &'a str,
+    map_output: &'a SourceMapOutput<'a>,
+    cache: Arc<PackageCache>,
+    disk_cache: Option<Arc<DiskCache>>,
+    jobs: usize,
+    // from the last successful build, by absolute path
+    modules: FnvHashMap<PathBuf, Module>,
+    // the files each require tried, and the manifests it looked for, and whether they existed; it
+    // resolves the same way until one of them appears or disappears
+    inputs: FnvHashMap<(PathBuf, String), Vec<(PathBuf, bool)>>,
+    // changed since the last successful build
+    stale: FnvHashSet<PathBuf>,
+    // if the last build failed, the modules it reached and the files a require that failed tried
+    failed: FnvHashSet<PathBuf>,
+    cancel: Cancel,
+    // keeps the bundle in memory for `scrumple serve` instead of writing it out
+    serve: bool,
+    served: Option<Served>,
+}
+
+// the last successful build's bundle, for `scrumple serve`, or just the live reload client
+// before there is one
+#[derive(Debug, Clone)]
+pub struct Served {
+    pub bundle: Arc<Vec<u8>>,
+    // None unless the source map is separate from the bundle
+    pub map: Option<Arc<Vec<u8>>>,
+}
+
+// cancels a build in progress from another thread, stopping its workers; a cancelled build
+// doesn't write anything, and leaves what changed for the next one
+#[derive(Debug, Clone, Default)]
+pub struct Cancel(Arc<CancelState>);
+
+#[derive(Debug, Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    // the running build's channel, to wake it up
+    build: Mutex<Option<mpsc::Sender<Result<WorkDone, CliError>>>>,
+}
+
+impl Cancel {
+    // cancels the build in progress, or the next one if there isn't one
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        if let Some(ref tx) = *self.0.build.lock().unwrap() {
+            let _ = tx.send(Err(CliError::Cancelled));
+        }
+    }
+
+    // forgets earlier cancellations
+    pub fn reset(&self) {
+        self.0.cancelled.store(false, Ordering::SeqCst);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    fn start(&self, tx: mpsc::Sender<Result<WorkDone, CliError>>) {
+        let mut build = self.0.build.lock().unwrap();
+        if self.is_cancelled() {
+            let _ = tx.send(Err(CliError::Cancelled));
+        }
+        *build = Some(tx);
+    }
+
+    fn finish(&self) {
+        *self.0.build.lock().unwrap() = None;
+    }
+}
+
+// what a build did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BuildStats {
+    pub modules: usize,
+    // read and transformed, rather than reused from the last build
+    pub included: usize,
+    pub resolved: usize,
+}
 
-    // TODO: context.require('…')
-    // TODO: watch for missing files on error?
-
-    let mut modules = FnvHashMap::<PathBuf, ModuleState>::default();
-
-    worker_init.add_work(Work::Include {
-        module: entry_point.to_owned(),
-    });
-    pending += 1;
-    modules.insert(entry_point.to_owned(), ModuleState::Loading);
-
-    let children: Vec<_> = (0..thread_count)
-        .map(|_| {
-            let init = worker_init.clone();
-            thread::spawn(move || Worker::new(init).run())
-        })
-        .collect();
-    // let children: Vec<_> = (0..thread_count).map(|n| {    //     let init = worker_init.clone();
-    //     thread::Builder::new().name(format!("worker #{}", n + 1)).spawn(move || Worker::new(init).run()).unwrap()
-    // }).collect();
-
-    while let Ok(work_done) = rx.recv() {
-        // eprintln!("{:?}", work_done);
-        let work_done = match work_done {
+impl fmt::Display for BuildStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} modules rebuilt, {} requires resolved",
+            self.included, self.modules, self.resolved
+        )
+    }
+}
+
+impl<'a> Bundler<'a> {
+    pub fn new(
+        entry_point: &Path,
+        input_options: InputOptions,
+        output: &'a str,
+        map_output: &'a SourceMapOutput<'a>,
+        cache: Arc<PackageCache>,
+        jobs: usize,
+    ) -> Self {
+        Bundler {
+            entry_point: real_path(entry_point.to_owned(), input_options.preserve_symlinks),
+            input_options,
+            output,
+            map_output,
+            cache,
+            disk_cache: None,
+            jobs,
+            modules: FnvHashMap::default(),
+            inputs: FnvHashMap::default(),
+            stale: FnvHashSet::default(),
+            failed: FnvHashSet::default(),
+            cancel: Cancel::default(),
+            serve: false,
+            served: None,
+        }
+    }
+
+    // keeps transformed modules and resolved requires in `disk_cache` for later runs, and uses
+    // the ones earlier runs kept
+    pub fn with_disk_cache(mut self, disk_cache: Arc<DiskCache>) -> Self {
+        self.disk_cache = Some(disk_cache);
+        self
+    }
+
+    // keeps each build's bundle in memory, with a client that reloads the page when it's rebuilt,
+    // instead of writing it out
+    pub fn serve(mut self) -> Self {
+        self.serve = true;
+        // until a build succeeds, just the client, so that the page can show why it didn't
+        self.served = Some(Served {
+            bundle: Arc::new(LIVE_RELOAD_JS.as_bytes().to_vec()),
+            map: None,
+        });
+        self
+    }
+
+    pub fn served(&self) -> Option<Served> {
+        self.served.clone()
+    }
+
+    // the modules in the bundle, by absolute path
+    #[cfg(test)]
+    pub fn modules(&self) -> &FnvHashMap<PathBuf, Module> {
+        &self.modules
+    }
+
+    pub fn cancel_handle(&self) -> Cancel {
+        self.cancel.clone()
+    }
+
+    // the files whose changes can affect the next build, some of which may not exist yet
+    pub fn watched(&self) -> FnvHashSet<PathBuf> {
+        let mut watched: FnvHashSet<_> = self.modules.keys().cloned().collect();
+        watched.extend(self.cache.manifest_files());
+        // the files that would change how something resolves by appearing
+        watched.extend(
+            self.inputs
+                .values()
+                .flatten()
+                .filter(|(_, existed)| !existed)
+                .map(|(path, _)| path.clone()),
+        );
+        watched.extend(self.failed.iter().cloned());
+        watched
+    }
+
+    // builds the bundle and writes it out; `changed` lists the files and directories that have
+    // changed since the last build
+    pub fn build(&mut self, changed: &[PathBuf]) -> Result<BuildStats, CliError> {
+        for path in changed {
+            self.cache.invalidate(path);
+            if let Some(ref disk_cache) = self.disk_cache {
+                disk_cache.invalidate(path);
+            }
+            self.stale.insert(path.clone());
+        }
+        let mut reached = FnvHashSet::default();
+        let loaded = self.load(&mut reached);
+        self.cancel.finish();
+        // it may have read some files before they changed and some after
+        if self.cancel.is_cancelled() {
+            return Err(CliError::Cancelled);
+        }
+        let (modules, inputs, mut stats) = match loaded {
+            Ok(loaded) => loaded,
             Err(error) => {
-                worker_init.quit.store(true, Ordering::Relaxed);
+                if let CliError::ModuleNotFound {
+                    ref context,
+                    ref tried,
+                    ..
+                } = error
+                {
+                    reached.insert(context.clone());
+                    reached.extend(tried.iter().cloned());
+                }
+                self.failed = reached;
                 return Err(error);
             }
-            Ok(work_done) => {
-                pending -= 1;
-                work_done
-            }
         };
-        match work_done {
-            WorkDone::Resolve {
-                context,
-                name,
-                resolved,
+        self.failed.clear();
+        stats.modules = modules.len();
+        self.modules = modules;
+        self.inputs = inputs;
+        self.stale.clear();
+        self.write()?;
+        Ok(stats)
+    }
+
+    fn is_stale(&self, path: &Path) -> bool {
+        self.stale.iter().any(|stale| path.starts_with(stale))
+    }
+
+    // the result of `work` in the last build, if nothing it depends on has changed since
+    fn reuse(&self, work: &Work, everything_stale: bool) -> Option<WorkDone> {
+        match *work {
+            Work::Include { ref module } => {
+                if self.is_stale(module) {
+                    return None;
+                }
+                let previous = self.modules.get(module)?;
+                Some(WorkDone::Include {
+                    module: module.clone(),
+                    info: ModuleInfo {
+                        source: Arc::clone(&previous.source),
+                        deps: previous.deps.keys().cloned().collect(),
+                    },
+                })
+            }
+            Work::Resolve {
+                ref context,
+                ref name,
             } => {
-                match *modules.get_mut(&context).unwrap() {
-                    ModuleState::Loading => unreachable!(),
-                    ModuleState::Loaded(Module { ref mut deps, .. }) => {
-                        deps.insert(name, resolved.clone());
-                    }
+                if everything_stale {
+                    return None;
                 }
-                match resolved {
-                    Resolved::External => {}
-                    Resolved::Ignore => {}
-                    Resolved::Normal(module) => {
-                        modules.entry(module.clone()).or_insert_with(|| {
-                            worker_init.add_work(Work::Include { module });
-                            pending += 1;
-                            ModuleState::Loading
-                        });
-                    }
+                let resolved = self.modules.get(context)?.deps.get(name)?;
+                let key = (context.clone(), name.clone());
+                let inputs = self.inputs.get(&key)?;
+                if inputs
+                    .iter()
+                    .any(|(input, existed)| self.is_stale(input) && input.is_file() != *existed)
+                {
+                    return None;
                 }
+                Some(WorkDone::Resolve {
+                    context: key.0,
+                    name: key.1,
+                    resolved: resolved.clone(),
+                    inputs: inputs.clone(),
+                })
             }
-            WorkDone::Include { module, info } => {
-                let old = modules.insert(
-                    module.clone(),
-                    ModuleState::Loaded(Module {
-                        source: info.source,
-                        deps: FnvHashMap::default(),
-                    }),
-                );
-                debug_assert_matches!(old, Some(ModuleState::Loading));
-                for dep in info.deps {
-                    worker_init.add_work(Work::Resolve {
-                        context: module.clone(),
-                        name: dep,
-                    });
-                    pending += 1;
+        }
+    }
+
+    // `reached` gets the modules reached before an error
+    #[allow(clippy::type_complexity)]
+    fn load(
+        &self,
+        reached: &mut FnvHashSet<PathBuf>,
+    ) -> Result<
+        (
+            FnvHashMap<PathBuf, Module>,
+            FnvHashMap<(PathBuf, String), Vec<(PathBuf, bool)>>,
+            BuildStats,
+        ),
+        CliError,
+    > {
+        // a manifest can change how anything resolves
+        let everything_stale = self.stale.iter().any(|path| manifest::is_manifest(path));
+        let mut stats = BuildStats::default();
+        let mut pending = 0;
+        let (tx, rx) = mpsc::channel();
+        self.cancel.start(tx.clone());
+        let (queue, deques) = WorkQueue::new(self.jobs);
+        let worker_init = WorkerInit {
+            tx,
+            input_options: self.input_options.clone(),
+            cache: Arc::clone(&self.cache),
+            disk_cache: self.disk_cache.clone(),
+            queue: Arc::new(queue),
+        };
+
+        // TODO: context.require('…')
+
+        let mut modules = FnvHashMap::<PathBuf, ModuleState>::default();
+        let mut inputs = FnvHashMap::default();
+        // work done in the last build that can be reused
+        let mut reused = Vec::new();
+        let mut add_work = |work: Work, pending: &mut usize, reused: &mut Vec<WorkDone>| match self
+            .reuse(&work, everything_stale)
+        {
+            Some(work_done) => reused.push(work_done),
+            None => {
+                match work {
+                    Work::Include { .. } => stats.included += 1,
+                    Work::Resolve { .. } => stats.resolved += 1,
+                }
+                worker_init.add_work(work);
+                *pending += 1;
+            }
+        };
+
+        add_work(
+            Work::Include {
+                module: self.entry_point.clone(),
+            },
+            &mut pending,
+            &mut reused,
+        );
+        modules.insert(self.entry_point.clone(), ModuleState::Loading);
+
+        let children: Vec<_> = deques
+            .into_iter()
+            .map(|local| {
+                let init = worker_init.clone();
+                thread::spawn(move || Worker::new(init, local).run())
+            })
+            .collect();
+        // let children: Vec<_> = (0..thread_count).map(|n| {    //     let init = worker_init.clone();
+        //     thread::Builder::new().name(format!("worker #{}", n + 1)).spawn(move || Worker::new(init).run()).unwrap()
+        // }).collect();
+
+        loop {
+            let work_done = match reused.pop() {
+                Some(work_done) => work_done,
+                None if pending == 0 => break,
+                None => match rx.recv() {
+                    Ok(Ok(work_done)) => {
+                        pending -= 1;
+                        work_done
+                    }
+                    Ok(Err(error)) => {
+                        worker_init.queue.quit();
+                        reached.extend(modules.into_keys());
+                        return Err(error);
+                    }
+                    Err(_) => break,
+                },
+            };
+            // eprintln!("{:?}", work_done);
+            match work_done {
+                WorkDone::Resolve {
+                    context,
+                    name,
+                    resolved,
+                    inputs: resolve_inputs,
+                } => {
+                    match *modules.get_mut(&context).unwrap() {
+                        ModuleState::Loading => unreachable!(),
+                        ModuleState::Loaded(Module { ref mut deps, .. }) => {
+                            deps.insert(name.clone(), resolved.clone());
+                        }
+                    }
+                    inputs.insert((context, name), resolve_inputs);
+                    match resolved {
+                        Resolved::External => {}
+                        Resolved::Ignore => {}
+                        Resolved::Normal(module) => {
+                            if !modules.contains_key(&module) {
+                                modules.insert(module.clone(), ModuleState::Loading);
+                                add_work(Work::Include { module }, &mut pending, &mut reused);
+                            }
+                        }
+                    }
+                }
+                WorkDone::Include { module, info } => {
+                    let old = modules.insert(
+                        module.clone(),
+                        ModuleState::Loaded(Module {
+                            source: info.source,
+                            deps: FnvHashMap::default(),
+                        }),
+                    );
+                    debug_assert_matches!(old, Some(ModuleState::Loading));
+                    for dep in info.deps {
+                        add_work(
+                            Work::Resolve {
+                                context: module.clone(),
+                                name: dep,
+                            },
+                            &mut pending,
+                            &mut reused,
+                        );
+                    }
                 }
             }
         }
-        if pending == 0 {
-            break;
+
+        worker_init.queue.quit();
+        for child in children {
+            child
+                .join()
+                .map_err(|payload| worker::panicked(None, payload))?;
         }
-    }
 
-    worker_init.quit.store(true, Ordering::Relaxed);
-    for child in children {
-        child.join()?;
+        let modules = modules
+            .into_iter()
+            .map(|(path, state)| (path, state.unwrap()))
+            .collect();
+        Ok((modules, inputs, stats))
     }
 
-    let writer = Writer {
-        modules: modules
+    // module paths in the bundle are relative to the entry point's directory
+    fn relative_to_entry(
+        &self,
+        modules: FnvHashMap<PathBuf, Module>,
+    ) -> FnvHashMap<PathBuf, Module> {
+        let parent = self.entry_point.parent().unwrap();
+        modules
             .into_iter()
-            .map(|(k, ms)| {
-                let parent = entry_point.parent().unwrap();
-                let ms = ms.unwrap();
+            .map(|(k, ms)| match k.as_path().strip_prefix(parent) {
+                Ok(path) => (PathBuf::from(path), ms),
+                Err(_) => (k, ms),
+            })
+            .collect()
+    }
 
-                match k.as_path().strip_prefix(parent) {
-                    Ok(path) => (PathBuf::from(path), ms),
-                    Err(_) => (k, ms),
+    fn write(&mut self) -> Result<(), CliError> {
+        let modules = mem::take(&mut self.modules);
+        let writer = Writer {
+            modules: self.relative_to_entry(modules),
+            entry_point: &self.entry_point,
+            map_output: self.map_output,
+            live_reload: self.serve,
+        };
+        let result = if self.serve {
+            match render(&writer, self.map_output) {
+                Ok(served) => {
+                    self.served = Some(served);
+                    Ok(())
                 }
-            })
-            .collect(),
-        entry_point,
-        map_output,
-    };
+                Err(error) => Err(error),
+            }
+        } else {
+            write_bundle(&writer, self.output, self.map_output)
+        };
 
-    match &*output {
+        // and back again; paths outside its directory were left absolute
+        let parent = self.entry_point.parent().unwrap();
+        self.modules = writer
+            .modules
+            .into_iter()
+            .map(|(path, module)| (parent.join(path), module))
+            .collect();
+        result
+    }
+}
+
+fn write_bundle(
+    writer: &Writer,
+    output: &str,
+    map_output: &SourceMapOutput,
+) -> Result<(), CliError> {
+    match output {
         "-" => {
             let stdout = io::stdout();
             let mut handle = stdout.lock();
             writer.write_to(&mut handle)?;
         }
         _ => {
-            let output = Path::new(output);
-            if let Some(parent) = output.parent() {
-                fs::create_dir_all(parent)?;
-            }
-            let file = fs::File::create(&output)?;
-            let mut buf_writer = io::BufWriter::new(file);
-            writer.write_to(&mut buf_writer)?;
+            let mut bundle = Vec::new();
+            writer.write_to(&mut bundle)?;
+            replace_file(Path::new(output), &bundle)?;
         }
     }
     match *map_output {
@@ -155,16 +515,56 @@ pub fn bundle(
             // handled in Writer::write_to()
         }
         SourceMapOutput::File(ref path, _) => {
-            if let Some(parent) = path.parent() {
-                fs::create_dir_all(parent)?;
-            }
-            let file = fs::File::create(path)?;
-            let mut buf_writer = io::BufWriter::new(file);
-            writer.write_map_to(&mut buf_writer)?;
+            let mut map = Vec::new();
+            writer.write_map_to(&mut map)?;
+            replace_file(path, &map)?;
         }
     }
-    // println!("entry point: {:?}", entry_point);
-    // println!("{:#?}", modules);
+    Ok(())
+}
 
-    Ok(writer.modules)
+fn render(writer: &Writer, map_output: &SourceMapOutput) -> Result<Served, CliError> {
+    let mut bundle = Vec::new();
+    writer.write_to(&mut bundle)?;
+    let map = match *map_output {
+        SourceMapOutput::File(..) => {
+            let mut map = Vec::new();
+            writer.write_map_to(&mut map)?;
+            Some(Arc::new(map))
+        }
+        _ => None,
+    };
+    Ok(Served {
+        bundle: Arc::new(bundle),
+        map,
+    })
+}
+
+// replaces the file at `path` with `contents` all at once, so nothing watching it sees half of
+// them, and leaves it alone if it already has them. anything but a regular file (a symlink,
+// /dev/null, a FIFO) is written to in place instead
+fn replace_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_file() => {
+            if fs::read(path).is_ok_and(|old| old == contents) {
+                return Ok(());
+            }
+        }
+        Ok(_) => return fs::write(path, contents),
+        Err(_) => {}
+    }
+    let parent = path.parent().unwrap_or_else(|| Path::new(""));
+    if !parent.as_os_str().is_empty() {
+        fs::create_dir_all(parent)?;
+    }
+    // in the same directory, since a rename can't cross filesystems
+    let mut temp_name = OsString::from(".");
+    temp_name.push(path.file_name().unwrap_or_default());
+    temp_name.push(format!(".{}.tmp", process::id()));
+    let temp = parent.join(temp_name);
+    let result = fs::write(&temp, contents).and_then(|_| fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
 }
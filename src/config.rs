@@ -0,0 +1,28 @@
+use crate::input_options::{Extensions, IndexFiles, MainFields};
+use crate::CliError;
+use fnv::FnvHashMap;
+use serde::Deserialize;
+use std::path::Path;
+use std::{fs, io};
+
+// settings read from --config <file>; options given on the command line take precedence
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase", deny_unknown_fields)]
+pub struct Config {
+    pub main_fields: Option<MainFields>,
+    pub resolve_extensions: Option<Extensions>,
+    pub index_files: Option<IndexFiles>,
+    pub alias: Option<FnvHashMap<String, String>>,
+    pub preserve_symlinks: Option<bool>,
+    pub strict_case: Option<bool>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, CliError> {
+        let file = fs::File::open(path)?;
+        serde_json::from_reader(io::BufReader::new(file)).map_err(|err| CliError::InvalidConfig {
+            path: path.to_owned(),
+            err,
+        })
+    }
+}
@@ -0,0 +1,259 @@
+use crate::es6::ModuleKind;
+use crate::input_options::{InputOptions, PackageManager};
+use crate::manifest::OnceMap;
+use crate::modules::{ModuleInfo, Source};
+use crate::resolver::Resolved;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// transformed modules and resolved requires, kept in a directory between runs (--cache-dir).
+// modules are keyed by their contents, so an entry can't go stale; resolutions are keyed by what
+// was required from where, and checked against the manifests and files they depended on before
+// they're used. an entry that can't be read or written is just a miss.
+#[derive(Debug)]
+pub struct DiskCache {
+    dir: PathBuf,
+    // the options that affect how requires resolve, as part of every resolution's key; None when
+    // resolutions depend on more than the files they tried and can't be cached
+    resolve_options: Option<String>,
+    // hashes of the manifests checked so far, None if they're missing
+    manifests: OnceMap<Option<String>>,
+    // makes the names of files being written unique
+    writes: AtomicUsize,
+    hits: AtomicUsize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ModuleEntry {
+    prefix: String,
+    // None when it's the file's contents unchanged
+    body: Option<String>,
+    suffix: String,
+    deps: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ResolutionEntry {
+    resolved: Resolved,
+    // the files tried, and whether they existed
+    inputs: Vec<(PathBuf, bool)>,
+    // the manifests read, and their hashes; None for the ones that were looked for and missing
+    manifests: Vec<(PathBuf, Option<String>)>,
+}
+
+impl DiskCache {
+    pub fn new(dir: PathBuf, input_options: &InputOptions) -> Result<Self, std::io::Error> {
+        fs::create_dir_all(dir.join("modules"))?;
+        fs::create_dir_all(dir.join("resolutions"))?;
+        // Plug'n'Play resolutions depend on .pnp.data.json, and strict case checks on directory
+        // listings
+        let resolve_options =
+            if input_options.package_manager == PackageManager::Pnp || input_options.strict_case {
+                None
+            } else {
+                let mut external: Vec<_> = input_options.external.iter().collect();
+                external.sort();
+                let mut forced_npm_deps: Vec<_> = input_options.forced_npm_deps.iter().collect();
+                forced_npm_deps.sort();
+                Some(format!(
+                    "{:?}",
+                    (
+                        input_options.package_manager,
+                        external,
+                        forced_npm_deps,
+                        &input_options.main_fields,
+                        &input_options.extensions,
+                        &input_options.index_files,
+                        &input_options.aliases,
+                        input_options.preserve_symlinks,
+                    )
+                ))
+            };
+        Ok(DiskCache {
+            dir,
+            resolve_options,
+            manifests: OnceMap::default(),
+            writes: AtomicUsize::new(0),
+            hits: AtomicUsize::new(0),
+        })
+    }
+
+    // entries used so far
+    #[cfg(test)]
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+
+    // forgets what it knows about `path` and everything below it
+    pub fn invalidate(&self, path: &Path) {
+        self.manifests.remove_under(path);
+    }
+
+    // the key for a module with the contents `source`, transformed as `kind`, or as JSON if None
+    pub fn module_key(kind: Option<ModuleKind>, source: &str) -> String {
+        Hash::new()
+            .part(crate::APP_VERSION)
+            .part(&format!("{:?}", kind))
+            .part(source)
+            .finish()
+    }
+
+    pub fn module(&self, key: &str, source: &str) -> Option<ModuleInfo> {
+        let entry: ModuleEntry = self.read(&self.dir.join("modules").join(key))?;
+        let source = match entry.body {
+            None => Source {
+                prefix: entry.prefix,
+                body: source.to_owned(),
+                suffix: entry.suffix,
+                original: None,
+            },
+            Some(body) => Source {
+                prefix: entry.prefix,
+                body,
+                suffix: entry.suffix,
+                original: Some(source.to_owned()),
+            },
+        };
+        self.hits.fetch_add(1, Ordering::SeqCst);
+        Some(ModuleInfo {
+            source: Arc::new(source),
+            deps: entry.deps,
+        })
+    }
+
+    pub fn store_module(&self, key: &str, info: &ModuleInfo) {
+        let entry = ModuleEntry {
+            prefix: info.source.prefix.clone(),
+            body: info
+                .source
+                .original
+                .as_ref()
+                .map(|_| info.source.body.clone()),
+            suffix: info.source.suffix.clone(),
+            deps: info.deps.clone(),
+        };
+        self.write(&self.dir.join("modules").join(key), &entry);
+    }
+
+    fn resolution_path(&self, context: &Path, name: &str) -> Option<PathBuf> {
+        let key = Hash::new()
+            .part(crate::APP_VERSION)
+            .part(self.resolve_options.as_ref()?)
+            .part(&*context.to_string_lossy())
+            .part(name)
+            .finish();
+        Some(self.dir.join("resolutions").join(key))
+    }
+
+    // what `name` resolved to from `context`, and the files it tried, if none of them has
+    // appeared or disappeared and none of the manifests it looked for has changed, appeared or
+    // disappeared since
+    pub fn resolution(
+        &self,
+        context: &Path,
+        name: &str,
+    ) -> Option<(Resolved, Vec<(PathBuf, bool)>)> {
+        let entry: ResolutionEntry = self.read(&self.resolution_path(context, name)?)?;
+        if entry
+            .inputs
+            .iter()
+            .any(|(input, existed)| input.is_file() != *existed)
+            || entry
+                .manifests
+                .iter()
+                .any(|(manifest, hash)| self.manifest_hash(manifest) != *hash)
+        {
+            return None;
+        }
+        self.hits.fetch_add(1, Ordering::SeqCst);
+        Some((entry.resolved, entry.inputs))
+    }
+
+    pub fn store_resolution(
+        &self,
+        context: &Path,
+        name: &str,
+        resolved: &Resolved,
+        inputs: &[(PathBuf, bool)],
+        manifests: &[PathBuf],
+    ) {
+        let path = match self.resolution_path(context, name) {
+            Some(path) => path,
+            None => return,
+        };
+        let manifests = manifests
+            .iter()
+            .map(|manifest| (manifest.clone(), self.manifest_hash(manifest)))
+            .collect();
+        let entry = ResolutionEntry {
+            resolved: resolved.clone(),
+            inputs: inputs.to_vec(),
+            manifests,
+        };
+        self.write(&path, &entry);
+    }
+
+    fn manifest_hash(&self, path: &Path) -> Option<String> {
+        self.manifests.get_or_init(path, || {
+            let contents = fs::read(path).ok()?;
+            Some(Hash::new().part(&contents).finish())
+        })
+    }
+
+    fn read<T: DeserializeOwned>(&self, path: &Path) -> Option<T> {
+        let contents = fs::read(path).ok()?;
+        serde_json::from_slice(&contents).ok()
+    }
+
+    // writes to a temporary file first, so that other threads and processes never read half an
+    // entry
+    fn write<T: Serialize>(&self, path: &Path, entry: &T) {
+        let contents = match serde_json::to_vec(entry) {
+            Ok(contents) => contents,
+            Err(_) => return,
+        };
+        let temp = path.with_extension(format!(
+            "{}-{}.tmp",
+            process::id(),
+            self.writes.fetch_add(1, Ordering::SeqCst)
+        ));
+        if fs::write(&temp, contents).is_err() || fs::rename(&temp, path).is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+    }
+}
+
+// 128-bit FNV-1a, which is stable across builds and platforms, unlike std's hashers
+struct Hash(u128);
+
+impl Hash {
+    fn new() -> Self {
+        Hash(0x6c62_272e_07bb_0142_62b8_2175_6295_c58d)
+    }
+
+    // each part is prefixed with its length, so ("ab", "c") and ("a", "bc") hash differently
+    fn part<B: AsRef<[u8]> + ?Sized>(mut self, bytes: &B) -> Self {
+        let bytes = bytes.as_ref();
+        self.write(&(bytes.len() as u64).to_le_bytes());
+        self.write(bytes);
+        self
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u128::from(byte);
+            self.0 = self
+                .0
+                .wrapping_mul(0x0000_0000_0100_0000_0000_0000_0000_013b);
+        }
+    }
+
+    fn finish(&self) -> String {
+        format!("{:032x}", self.0)
+    }
+}
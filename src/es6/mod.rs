@@ -57,6 +57,16 @@ impl ImportBindingNames {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ModuleKind {
+    // strict ES module: import/export only, require() is left alone
+    Esm,
+    // CommonJS: require() only, import/export are not rewritten
+    CommonJs,
+    // either; it's a module if it contains any imports or exports
+    Detect,
+}
+
 #[derive(Debug)]
 pub struct CjsModule<'s> {
     pub source_prefix: String,
@@ -67,8 +77,10 @@ pub struct CjsModule<'s> {
 
 pub fn module_to_cjs<'f, 's>(
     lex: &mut lex::Lexer<'f, 's>,
-    allow_require: bool,
+    kind: ModuleKind,
 ) -> Result<CjsModule<'s>> {
+    let allow_require = kind != ModuleKind::Esm;
+    let allow_esm = kind != ModuleKind::CommonJs;
     let mut source = String::new();
     let mut deps = FnvHashSet::default();
     let mut imports = Vec::new();
@@ -80,11 +92,11 @@ pub fn module_to_cjs<'f, 's>(
     // and optionally cjs requires
     loop {
         eat!(lex => tok { source.push_str(tok.ws_before) },
-            Tt::Export => {
+            Tt::Export if allow_esm => {
                 let export = parse_export(lex, &mut source)?;
                 exports.push(export);
             },
-            Tt::Import => {
+            Tt::Import if allow_esm => {
                 match parse_import(lex, &mut source)? {
                     ParsedImport::Import(import) => {
                         imports.push(import);
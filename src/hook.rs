@@ -0,0 +1,311 @@
+use std::ffi::OsString;
+use std::io;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+#[cfg(unix)]
+use std::sync::atomic::{AtomicI32, Ordering};
+#[cfg(unix)]
+use std::sync::Once;
+use std::time::Duration;
+
+// the commands run after each build in watch mode (--on-success, --on-error), through the shell so
+// that they can be pipelines. only one runs at a time: whatever's left of the last one is killed
+// before the next starts, since it was working with an output that's since been replaced.
+#[derive(Debug, Default)]
+pub struct Hooks {
+    pub on_success: Option<String>,
+    pub on_error: Option<String>,
+    running: Option<Child>,
+}
+
+// what a hook is told about the build, in its environment
+#[derive(Debug)]
+pub struct HookEnv<'a> {
+    pub output: &'a str,
+    pub duration: Duration,
+    // since the last build that wasn't cancelled
+    pub changed: &'a [PathBuf],
+    // None after a successful build
+    pub error: Option<String>,
+}
+
+impl Hooks {
+    // runs the hook for how the build went, if there is one, and returns the command if it
+    // couldn't be started
+    pub fn run(&mut self, env: HookEnv) -> Result<(), (String, io::Error)> {
+        let command = match env.error {
+            None => self.on_success.as_ref(),
+            Some(_) => self.on_error.as_ref(),
+        };
+        let command = match command {
+            Some(command) => command.clone(),
+            None => return Ok(()),
+        };
+        self.kill();
+        match shell(&command, &env).spawn() {
+            Ok(child) => {
+                set_running(Some(child.id()));
+                self.running = Some(child);
+                Ok(())
+            }
+            Err(error) => Err((command, error)),
+        }
+    }
+
+    // forgets the last one if it's finished, so that it doesn't linger as a zombie
+    pub fn reap(&mut self) {
+        let finished = match self.running {
+            Some(ref mut child) => !matches!(child.try_wait(), Ok(None)),
+            None => false,
+        };
+        if finished {
+            set_running(None);
+            self.running = None;
+        }
+    }
+
+    // the shell and everything it started, which would otherwise outlive it
+    fn kill(&mut self) {
+        if let Some(mut child) = self.running.take() {
+            kill_tree(&mut child);
+            set_running(None);
+            let _ = child.wait();
+        }
+    }
+}
+
+impl Drop for Hooks {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+#[cfg(unix)]
+fn shell_command(command: &str) -> Command {
+    use std::os::unix::process::CommandExt;
+
+    let mut shell = Command::new("sh");
+    // in a process group of its own, for kill_tree
+    shell.arg("-c").arg(command).process_group(0);
+    shell
+}
+
+#[cfg(windows)]
+fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+// the shell leads a process group of its own, whose id is its pid. it hasn't been waited for, so
+// that's still its own
+#[cfg(unix)]
+fn kill_tree(child: &mut Child) {
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(windows)]
+fn kill_tree(child: &mut Child) {
+    let killed = Command::new("taskkill")
+        .args(["/T", "/F", "/PID"])
+        .arg(child.id().to_string())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    if !killed.is_ok_and(|status| status.success()) {
+        let _ = child.kill();
+    }
+}
+
+// the process group of the hook that's running, for on_signal
+#[cfg(unix)]
+static RUNNING: AtomicI32 = AtomicI32::new(0);
+
+// Ctrl-C only reaches scrumple's process group, which the hooks aren't in, so they're killed on
+// the way out
+#[cfg(unix)]
+fn set_running(pid: Option<u32>) {
+    static HANDLERS: Once = Once::new();
+    HANDLERS.call_once(|| {
+        for &signal in &[libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            unsafe {
+                // but not if it's being ignored, as under nohup
+                if libc::signal(
+                    signal,
+                    on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+                ) == libc::SIG_IGN
+                {
+                    libc::signal(signal, libc::SIG_IGN);
+                }
+            }
+        }
+    });
+    RUNNING.store(pid.map_or(0, |pid| pid as i32), Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+extern "C" fn on_signal(signal: libc::c_int) {
+    let group = RUNNING.load(Ordering::SeqCst);
+    unsafe {
+        if group > 0 {
+            libc::kill(-group, libc::SIGKILL);
+        }
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+// the console sends Ctrl-C to every process attached to it
+#[cfg(windows)]
+fn set_running(_pid: Option<u32>) {}
+
+fn shell(command: &str, env: &HookEnv) -> Command {
+    let mut changed = OsString::new();
+    for (i, path) in env.changed.iter().enumerate() {
+        if i > 0 {
+            changed.push("\n");
+        }
+        changed.push(path);
+    }
+    let duration_ms = env.duration.as_secs() * 1_000 + u64::from(env.duration.subsec_millis());
+
+    let mut shell = shell_command(command);
+    shell
+        .stdin(Stdio::null())
+        .env("SCRUMPLE_OUTPUT", env.output)
+        .env("SCRUMPLE_DURATION_MS", duration_ms.to_string())
+        .env("SCRUMPLE_CHANGED", changed);
+    match env.error {
+        Some(ref error) => shell.env("SCRUMPLE_ERROR", error),
+        None => shell.env_remove("SCRUMPLE_ERROR"),
+    };
+    shell
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::path::Path;
+    use std::{fs, thread};
+
+    fn env<'a>(changed: &'a [PathBuf], error: Option<&str>) -> HookEnv<'a> {
+        HookEnv {
+            output: "out.js",
+            duration: Duration::from_millis(12),
+            changed,
+            error: error.map(|error| error.to_owned()),
+        }
+    }
+
+    fn wait_for(path: &Path) -> String {
+        for _ in 0..200 {
+            if let Ok(contents) = fs::read_to_string(path) {
+                if contents.ends_with('\n') {
+                    return contents;
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("{} was never written", path.display());
+    }
+
+    #[test]
+    fn test_hooks() {
+        let dir = tempfile::tempdir().unwrap();
+        let success = dir.path().join("success");
+        let error = dir.path().join("error");
+        let mut hooks = Hooks::default();
+        hooks.on_success = Some(format!(
+            r#"printf '%s %s %s\n' "$SCRUMPLE_OUTPUT" "$SCRUMPLE_DURATION_MS" "$SCRUMPLE_CHANGED" > '{}'"#,
+            success.display()
+        ));
+        hooks.on_error = Some(format!(
+            r#"printf '%s\n' "$SCRUMPLE_ERROR" > '{}'"#,
+            error.display()
+        ));
+        let changed = [PathBuf::from("/a.js"), PathBuf::from("/b.js")];
+
+        hooks.run(env(&changed, None)).unwrap();
+        assert_eq!(wait_for(&success), "out.js 12 /a.js\n/b.js\n");
+        hooks.run(env(&changed, Some("oops"))).unwrap();
+        assert_eq!(wait_for(&error), "oops\n");
+    }
+
+    // whether `pid` is a process that hasn't exited
+    fn running(pid: &str) -> bool {
+        let ps = Command::new("ps")
+            .args(["-o", "stat=", "-p", pid])
+            .output()
+            .unwrap();
+        let stat = String::from_utf8(ps.stdout).unwrap();
+        !stat.trim().is_empty() && !stat.trim().starts_with('Z')
+    }
+
+    fn exits(pid: &str) -> bool {
+        (0..200).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            !running(pid)
+        })
+    }
+
+    #[test]
+    fn test_hook_killed() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid = dir.path().join("pid");
+        let mut hooks = Hooks::default();
+        hooks.on_success = Some(format!("sleep 30 & echo $! > '{}'; wait", pid.display()));
+
+        hooks.run(env(&[], None)).unwrap();
+        let first = wait_for(&pid);
+        let first = first.trim();
+        assert!(running(first));
+        fs::remove_file(&pid).unwrap();
+
+        // the first is killed, along with the sleep it started
+        hooks.run(env(&[], None)).unwrap();
+        let second = wait_for(&pid);
+        assert!(exits(first));
+        assert!(running(second.trim()));
+        hooks.kill();
+        assert!(exits(second.trim()));
+    }
+
+    #[test]
+    fn test_hook_killed_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid = dir.path().join("pid");
+        let mut hooks = Hooks::default();
+        hooks.on_success = Some(format!("sleep 30 & echo $! > '{}'; wait", pid.display()));
+
+        hooks.run(env(&[], None)).unwrap();
+        let sleep = wait_for(&pid);
+        drop(hooks);
+        assert!(exits(sleep.trim()));
+    }
+
+    #[test]
+    fn test_hook_reaped() {
+        let mut hooks = Hooks::default();
+        hooks.on_success = Some("true".to_owned());
+
+        hooks.run(env(&[], None)).unwrap();
+        let shell = hooks.running.as_ref().unwrap().id().to_string();
+        for _ in 0..200 {
+            hooks.reap();
+            if hooks.running.is_none() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(hooks.running.is_none());
+        // not left as a zombie
+        assert!(Command::new("ps")
+            .args(["-o", "stat=", "-p", &shell])
+            .output()
+            .unwrap()
+            .stdout
+            .is_empty());
+    }
+}
@@ -1,16 +1,23 @@
+use crate::path_ext::*;
 use fnv::FnvHashSet;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum PackageManager {
     Bower,
     Npm,
+    // npm packages, located through Yarn Plug'n'Play's .pnp.data.json where it exists
+    Pnp,
 }
 
 impl PackageManager {
     pub fn dir(&self) -> &'static str {
         match self {
             Self::Bower => "bower_components",
-            Self::Npm => "node_modules",
+            Self::Npm | Self::Pnp => "node_modules",
         }
     }
 
@@ -18,7 +25,7 @@ impl PackageManager {
     pub fn files(&self) -> Vec<&'static str> {
         match self {
             Self::Bower => vec![".bower.json", "bower.json"],
-            Self::Npm => vec!["package.json"],
+            Self::Npm | Self::Pnp => vec!["package.json"],
         }
     }
 }
@@ -29,9 +36,162 @@ impl Default for PackageManager {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MainField {
+    Browser,
+    Module,
+    Main,
+}
+
+impl FromStr for MainField {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "browser" => Ok(Self::Browser),
+            "module" => Ok(Self::Module),
+            "main" => Ok(Self::Main),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for MainField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Browser => "browser",
+            Self::Module => "module",
+            Self::Main => "main",
+        })
+    }
+}
+
+// the package.json fields that may name a package's entry point, in order of preference
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct MainFields(pub Vec<MainField>);
+
+impl Default for MainFields {
+    fn default() -> MainFields {
+        MainFields(vec![MainField::Browser, MainField::Main])
+    }
+}
+
+// extensions tried after the exact path, in order
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "Vec<String>")]
+pub struct Extensions(pub Vec<String>);
+
+impl Default for Extensions {
+    fn default() -> Extensions {
+        Extensions::from(vec![".mjs", ".js", ".cjs", ".json"])
+    }
+}
+
+impl<S: AsRef<str>> From<Vec<S>> for Extensions {
+    fn from(extensions: Vec<S>) -> Extensions {
+        Extensions(
+            extensions
+                .iter()
+                .map(|ext| {
+                    let ext = ext.as_ref();
+                    if ext.is_empty() || ext.starts_with('.') {
+                        ext.to_owned()
+                    } else {
+                        format!(".{}", ext)
+                    }
+                })
+                .collect(),
+        )
+    }
+}
+
+// files tried in a directory that isn't a package, in order
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct IndexFiles(pub Vec<String>);
+
+impl Default for IndexFiles {
+    fn default() -> IndexFiles {
+        IndexFiles(vec![
+            "index.mjs".to_owned(),
+            "index.js".to_owned(),
+            "index.json".to_owned(),
+        ])
+    }
+}
+
+// `from` matches a specifier exactly, or as a prefix: "pkg" also matches "pkg/…", and
+// "prefix/" matches anything starting with it. A trailing $ ("pkg$") allows exact matches only.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alias {
+    pub from: String,
+    pub to: String,
+    pub exact: bool,
+}
+
+impl Alias {
+    // relative targets are resolved against `base`
+    pub fn new(from: &str, to: &str, base: &Path) -> Alias {
+        let (from, exact) = match from.strip_suffix('$') {
+            Some(from) => (from, true),
+            None => (from, false),
+        };
+        let to = if Path::new(to).is_explicitly_relative() {
+            let mut path = base.to_owned();
+            path.append_resolving(to);
+            let mut to = path.to_string_lossy().into_owned();
+            if from.ends_with('/') && !to.ends_with('/') {
+                to.push('/');
+            }
+            to
+        } else {
+            to.to_owned()
+        };
+        Alias {
+            from: from.to_owned(),
+            to,
+            exact,
+        }
+    }
+
+    pub fn apply(&self, name: &str) -> Option<String> {
+        if name == self.from {
+            return Some(self.to.clone());
+        }
+        if self.exact {
+            return None;
+        }
+        let rest = name.strip_prefix(self.from.as_str())?;
+        if self.from.ends_with('/') || rest.starts_with('/') {
+            Some(format!("{}{}", self.to, rest))
+        } else {
+            None
+        }
+    }
+}
+
+// most specific first
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Aliases(pub Vec<Alias>);
+
+impl Aliases {
+    pub fn new(mut aliases: Vec<Alias>) -> Aliases {
+        aliases.sort_by(|a, b| b.from.len().cmp(&a.from.len()).then(a.from.cmp(&b.from)));
+        Aliases(aliases)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct InputOptions {
     pub package_manager: PackageManager,
     pub external: FnvHashSet<String>,
     pub forced_npm_deps: FnvHashSet<String>,
+    pub main_fields: MainFields,
+    pub extensions: Extensions,
+    pub index_files: IndexFiles,
+    pub aliases: Aliases,
+    pub preserve_symlinks: bool,
+    pub strict_case: bool,
 }
@@ -0,0 +1,21 @@
+  if (typeof window !== 'undefined' && typeof EventSource !== 'undefined') (function() {
+    // the dev server that served this script, which needn't be the page's
+    var script = document.currentScript
+    var events = new EventSource(script && script.src ? new URL('/_scrumple/events', script.src).href : '/_scrumple/events')
+    var overlay = null
+    events.addEventListener('success', function() {
+      location.reload()
+    })
+    events.addEventListener('failure', function(event) {
+      if (!overlay) {
+        overlay = document.createElement('pre')
+        overlay.title = 'Click to dismiss'
+        overlay.style.cssText = 'position: fixed; top: 0; right: 0; bottom: 0; left: 0; z-index: 2147483647; margin: 0; padding: 2em; overflow: auto; white-space: pre-wrap; background: rgba(0, 0, 0, 0.9); color: #ff8080; font: 14px/1.5 monospace'
+        overlay.addEventListener('click', function() {
+          overlay.parentNode.removeChild(overlay)
+        })
+      }
+      overlay.textContent = 'scrumple: ' + JSON.parse(event.data)
+      if (!overlay.parentNode) document.documentElement.appendChild(overlay)
+    })
+  })()
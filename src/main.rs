@@ -7,29 +7,48 @@ use lazy_static::lazy_static;
 use notify::Watcher;
 use regex::Regex;
 use serde::Deserialize;
-use std::any::Any;
 use std::ffi::OsString;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, RwLock};
 
-use std::{env, fmt, io, iter, process, str, string, thread, time};
+use std::{env, fmt, fs, io, process, str, string, thread, time};
 
 mod bundler;
+mod config;
+mod disk_cache;
 mod es6;
+mod hook;
 mod input_options;
 mod manifest;
 mod modules;
 mod opts;
 mod path_ext;
+mod pnp;
+mod poll;
+mod reporter;
 mod resolver;
+mod serve;
 mod source_maps;
 mod vlq;
 mod worker;
 mod writer;
 
-use bundler::bundle;
-use input_options::{InputOptions, PackageManager};
+#[cfg(test)]
+use bundler::{bundle, BuildStats};
+use bundler::{Bundler, Cancel};
+use config::Config;
+use disk_cache::DiskCache;
+use hook::{HookEnv, Hooks};
+use input_options::{
+    Alias, Aliases, Extensions, IndexFiles, InputOptions, MainFields, PackageManager,
+};
+use manifest::PackageCache;
+use path_ext::*;
+use poll::Poller;
+use reporter::Reporter;
 use resolver::{Resolved, Resolver};
+use serve::Server;
 use source_maps::SourceMapOutput;
 
 const CORE_MODULES: &[&str] = &[
@@ -62,6 +81,10 @@ const CORE_MODULES: &[&str] = &[
     "zlib",
 ];
 
+lazy_static! {
+    static ref COMMA: Regex = Regex::new(r#"\s*,\s*"#).unwrap();
+}
+
 pub fn npm_install(dir: &Path) {
     let node_modules = dir.join("node_modules");
     if node_modules.is_dir() {
@@ -156,10 +179,7 @@ fn recurse_npm_deps(root: &PathBuf, mut names: &mut FnvHashSet<String>) -> Resul
                             }
                             None => {}
                         }
-                        return Err(CliError::ModuleNotFound {
-                            context: root.to_owned(),
-                            name: key.to_owned(),
-                        });
+                        return Err(CliError::module_not_found(root, key));
                     }
                 }
             }
@@ -169,7 +189,7 @@ fn recurse_npm_deps(root: &PathBuf, mut names: &mut FnvHashSet<String>) -> Resul
     Ok(())
 }
 
-pub fn gather_npm_dev_deps(input: &String) -> Result<FnvHashSet<String>, CliError> {
+pub fn gather_npm_dev_deps(input: &str) -> Result<FnvHashSet<String>, CliError> {
     let mut pj_path = std::path::PathBuf::from(input);
     let mut dev_deps_and_their_deps = FnvHashSet::default();
 
@@ -202,23 +222,189 @@ pub fn gather_npm_dev_deps(input: &String) -> Result<FnvHashSet<String>, CliErro
     Ok(dev_deps_and_their_deps)
 }
 
+// options that change how modules are resolved, which `scrumple resolve` takes too
+#[derive(Debug, Default)]
+struct ResolveOptions {
+    package_manager: PackageManager,
+    external: FnvHashSet<String>,
+    wants_npm_dev_deps: bool,
+    main_fields: Option<MainFields>,
+    extensions: Option<Extensions>,
+    index_files: Option<IndexFiles>,
+    aliases: Vec<(String, String)>,
+    preserve_symlinks: bool,
+    strict_case: bool,
+    config_file: Option<String>,
+}
+
+impl ResolveOptions {
+    // returns false if `opt` isn't a resolve option
+    fn parse<I: Iterator<Item = String>>(
+        &mut self,
+        opt: String,
+        iter: &mut opts::Expand<I>,
+    ) -> Result<bool, CliError> {
+        match &*opt {
+            "-b" | "--for-bower" => self.package_manager = PackageManager::Bower,
+            "--pnp" => self.package_manager = PackageManager::Pnp,
+            "--preserve-symlinks" => self.preserve_symlinks = true,
+            "--strict-case" => self.strict_case = true,
+            "-x" | "--external" => {
+                let mods = iter.next_arg().ok_or(CliError::MissingOptionValue(opt))?;
+                for m in COMMA.split(&mods) {
+                    self.external.insert(m.to_string());
+                }
+            }
+            "--external-core" => {
+                for m in CORE_MODULES {
+                    self.external.insert(m.to_string());
+                }
+            }
+            "--main-fields" => {
+                if self.main_fields.is_some() {
+                    return Err(CliError::DuplicateOption(opt));
+                }
+                let fields = iter
+                    .next_arg()
+                    .ok_or_else(|| CliError::MissingOptionValue(opt.clone()))?;
+                self.main_fields = Some(MainFields(
+                    COMMA
+                        .split(&fields)
+                        .map(|field| {
+                            field.parse().map_err(|_| {
+                                CliError::InvalidOptionValue(opt.clone(), field.to_owned())
+                            })
+                        })
+                        .collect::<Result<_, _>>()?,
+                ));
+            }
+            "--resolve-extensions" => {
+                if self.extensions.is_some() {
+                    return Err(CliError::DuplicateOption(opt));
+                }
+                let exts = iter.next_arg().ok_or(CliError::MissingOptionValue(opt))?;
+                self.extensions = Some(Extensions::from(COMMA.split(&exts).collect::<Vec<_>>()));
+            }
+            "--index-files" => {
+                if self.index_files.is_some() {
+                    return Err(CliError::DuplicateOption(opt));
+                }
+                let files = iter.next_arg().ok_or(CliError::MissingOptionValue(opt))?;
+                self.index_files = Some(IndexFiles(
+                    COMMA.split(&files).map(ToOwned::to_owned).collect(),
+                ));
+            }
+            "-a" | "--alias" => {
+                let pairs = iter
+                    .next_arg()
+                    .ok_or_else(|| CliError::MissingOptionValue(opt.clone()))?;
+                for pair in COMMA.split(&pairs) {
+                    let mut parts = pair.splitn(2, '=');
+                    match (parts.next(), parts.next()) {
+                        (Some(from), Some(to)) if !from.is_empty() && !to.is_empty() => {
+                            self.aliases.push((from.to_owned(), to.to_owned()))
+                        }
+                        _ => return Err(CliError::InvalidOptionValue(opt, pair.to_owned())),
+                    }
+                }
+            }
+            "-c" | "--config" => {
+                if self.config_file.is_some() {
+                    return Err(CliError::DuplicateOption(opt));
+                }
+                self.config_file = Some(iter.next_arg().ok_or(CliError::MissingOptionValue(opt))?)
+            }
+            "-N" | "--allow-npm-dev-deps" => {
+                self.wants_npm_dev_deps = true;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // `input` is the module whose package.json --allow-npm-dev-deps reads
+    fn into_input_options(self, input: &str, input_dir: &Path) -> Result<InputOptions, CliError> {
+        let (config, config_dir) = match self.config_file {
+            Some(path) => {
+                let path = input_dir.join(path);
+                let config = Config::load(&path)?;
+                (config, path.parent().unwrap().to_owned())
+            }
+            None => (Config::default(), input_dir.to_owned()),
+        };
+
+        // aliases given on the command line replace any with the same name from the config
+        let mut alias_table: Vec<_> = self
+            .aliases
+            .iter()
+            .map(|(from, to)| Alias::new(from, to, input_dir))
+            .collect();
+        for (from, to) in config.alias.iter().flatten() {
+            if !self.aliases.iter().any(|(f, _)| f == from) {
+                alias_table.push(Alias::new(from, to, &config_dir));
+            }
+        }
+
+        let forced_npm_deps = if self.wants_npm_dev_deps {
+            gather_npm_dev_deps(input)?
+        } else {
+            FnvHashSet::default()
+        };
+
+        Ok(InputOptions {
+            package_manager: self.package_manager,
+            external: self.external,
+            forced_npm_deps,
+            main_fields: self.main_fields.or(config.main_fields).unwrap_or_default(),
+            extensions: self
+                .extensions
+                .or(config.resolve_extensions)
+                .unwrap_or_default(),
+            index_files: self.index_files.or(config.index_files).unwrap_or_default(),
+            aliases: Aliases::new(alias_table),
+            preserve_symlinks: self.preserve_symlinks
+                || config.preserve_symlinks.unwrap_or_default(),
+            strict_case: self.strict_case || config.strict_case.unwrap_or_default(),
+        })
+    }
+}
+
+// for `scrumple serve` without --port
+const DEFAULT_PORT: u16 = 8000;
+
+// for --watch-poll without an interval
+const DEFAULT_POLL_INTERVAL: time::Duration = time::Duration::from_millis(500);
+
 fn run() -> Result<(), CliError> {
+    if env::args().nth(1).as_deref() == Some("resolve") {
+        return run_resolve(opts::expand(env::args().skip(2)));
+    }
+
+    let serve = env::args().nth(1).as_deref() == Some("serve");
     let entry_inst = time::Instant::now();
 
     let mut input = None;
     let mut output = None;
     let mut map = None;
-    let mut package_manager = PackageManager::default();
     let mut map_inline = false;
     let mut no_map = false;
     let mut watch = false;
     let mut quiet_watch = false;
-    let mut external = FnvHashSet::default();
-    let mut forced_npm_deps = FnvHashSet::default();
-    let mut wants_npm_dev_deps = false;
+    let mut watch_poll = None;
+    let mut reporter = None;
+    let mut hooks = Hooks::default();
+    let mut jobs = None;
+    let mut cache_dir = None;
+    let mut port = None;
+    let mut static_dir = None;
+    let mut resolve_options = ResolveOptions::default();
 
     // TODO replace this arg parser
-    let mut iter = opts::args();
+    let mut iter = if serve {
+        opts::expand(env::args().skip(2))
+    } else {
+        opts::args()
+    };
     while let Some(arg) = iter.next() {
         let opt = match arg {
             opts::Arg::Pos(arg) => {
@@ -241,56 +427,112 @@ fn run() -> Result<(), CliError> {
                 watch = true;
                 quiet_watch = true;
             }
-            "-I" | "--map-inline" => map_inline = true,
-            "-M" | "--no-map" => no_map = true,
-            "-b" | "--for-bower" => package_manager = PackageManager::Bower,
-            "-x" | "--external" => {
-                lazy_static! {
-                    static ref COMMA: Regex = Regex::new(r#"\s*,\s*"#).unwrap();
+            "--watch-poll" => {
+                watch = true;
+                watch_poll = Some(DEFAULT_POLL_INTERVAL);
+            }
+            "--reporter" => {
+                if reporter.is_some() {
+                    return Err(CliError::DuplicateOption(opt));
                 }
-                let mods = iter
+                let name = iter
                     .next_arg()
-                    .ok_or_else(|| CliError::MissingOptionValue(opt))?;
-                for m in COMMA.split(&mods) {
-                    external.insert(m.to_string());
+                    .ok_or_else(|| CliError::MissingOptionValue(opt.clone()))?;
+                reporter = Some(parse_reporter(opt, name)?);
+            }
+            _ if opt.starts_with("--reporter=") => {
+                if reporter.is_some() {
+                    return Err(CliError::DuplicateOption("--reporter".to_owned()));
                 }
+                let name = opt["--reporter=".len()..].to_owned();
+                reporter = Some(parse_reporter("--reporter".to_owned(), name)?);
             }
-            "--external-core" => {
-                for m in CORE_MODULES {
-                    external.insert(m.to_string());
+            "--on-success" => {
+                if hooks.on_success.is_some() {
+                    return Err(CliError::DuplicateOption(opt));
+                }
+                hooks.on_success = Some(iter.next_arg().ok_or(CliError::MissingOptionValue(opt))?)
+            }
+            "--on-error" => {
+                if hooks.on_error.is_some() {
+                    return Err(CliError::DuplicateOption(opt));
+                }
+                hooks.on_error = Some(iter.next_arg().ok_or(CliError::MissingOptionValue(opt))?)
+            }
+            _ if opt.starts_with("--watch-poll=") => {
+                let ms = &opt["--watch-poll=".len()..];
+                watch_poll = match ms.parse() {
+                    Ok(ms) if ms > 0 => Some(time::Duration::from_millis(ms)),
+                    _ => {
+                        return Err(CliError::InvalidOptionValue(
+                            "--watch-poll".to_owned(),
+                            ms.to_owned(),
+                        ))
+                    }
+                };
+                watch = true;
+            }
+            "-j" | "--jobs" => {
+                if jobs.is_some() {
+                    return Err(CliError::DuplicateOption(opt));
+                }
+                let n = iter
+                    .next_arg()
+                    .ok_or_else(|| CliError::MissingOptionValue(opt.clone()))?;
+                jobs = match n.parse() {
+                    Ok(n) if n > 0 => Some(n),
+                    _ => return Err(CliError::InvalidOptionValue(opt, n)),
+                };
+            }
+            "--cache-dir" => {
+                if cache_dir.is_some() {
+                    return Err(CliError::DuplicateOption(opt));
+                }
+                cache_dir = Some(iter.next_arg().ok_or(CliError::MissingOptionValue(opt))?)
+            }
+            "--port" if serve => {
+                if port.is_some() {
+                    return Err(CliError::DuplicateOption(opt));
+                }
+                let n = iter
+                    .next_arg()
+                    .ok_or_else(|| CliError::MissingOptionValue(opt.clone()))?;
+                port = match n.parse::<u16>() {
+                    Ok(n) => Some(n),
+                    _ => return Err(CliError::InvalidOptionValue(opt, n)),
+                };
+            }
+            "--static" if serve => {
+                if static_dir.is_some() {
+                    return Err(CliError::DuplicateOption(opt));
                 }
+                static_dir = Some(iter.next_arg().ok_or(CliError::MissingOptionValue(opt))?)
             }
+            "-I" | "--map-inline" => map_inline = true,
+            "-M" | "--no-map" => no_map = true,
             "-m" | "--map" => {
                 if map.is_some() {
                     return Err(CliError::DuplicateOption(opt));
                 }
-                map = Some(
-                    iter.next_arg()
-                        .ok_or_else(|| CliError::MissingOptionValue(opt))?,
-                )
+                map = Some(iter.next_arg().ok_or(CliError::MissingOptionValue(opt))?)
             }
             "-i" | "--input" => {
                 if input.is_some() {
                     return Err(CliError::DuplicateOption(opt));
                 }
-                input = Some(
-                    iter.next_arg()
-                        .ok_or_else(|| CliError::MissingOptionValue(opt))?,
-                )
-            }
-            "-N" | "--allow-npm-dev-deps" => {
-                wants_npm_dev_deps = true;
+                input = Some(iter.next_arg().ok_or(CliError::MissingOptionValue(opt))?)
             }
             "-o" | "--output" => {
                 if output.is_some() {
                     return Err(CliError::DuplicateOption(opt));
                 }
-                output = Some(
-                    iter.next_arg()
-                        .ok_or_else(|| CliError::MissingOptionValue(opt))?,
-                )
+                output = Some(iter.next_arg().ok_or(CliError::MissingOptionValue(opt))?)
+            }
+            _ => {
+                if !resolve_options.parse(opt.clone(), &mut iter)? {
+                    return Err(CliError::UnknownOption(opt));
+                }
             }
-            _ => return Err(CliError::UnknownOption(opt)),
         }
     }
 
@@ -302,7 +544,8 @@ fn run() -> Result<(), CliError> {
 
     let input = input.ok_or(CliError::MissingFileName)?;
     let input_dir = env::current_dir()?;
-    let output = output.unwrap_or_else(|| "-".to_owned());
+    // when serving, the path the bundle is served at
+    let output = output.unwrap_or_else(|| if serve { "bundle.js" } else { "-" }.to_owned());
 
     let map_output = if map_inline {
         SourceMapOutput::Inline
@@ -323,96 +566,424 @@ fn run() -> Result<(), CliError> {
         }
     };
 
-    if wants_npm_dev_deps {
-        forced_npm_deps = gather_npm_dev_deps(&input)?;
-    }
+    let input_options = resolve_options.into_input_options(&input, &input_dir)?;
 
-    let input_options = InputOptions {
-        package_manager,
-        external,
-        forced_npm_deps,
-    };
-
-    let entry_point = match Resolver::new(input_options.clone()).resolve_main(input_dir, &input)? {
+    let jobs = jobs.unwrap_or_else(num_cpus::get);
+    let cache = Arc::new(PackageCache::new(input_options.main_fields.clone()));
+    let entry_point = match Resolver::with_cache(input_options.clone(), Arc::clone(&cache))
+        .resolve_main(input_dir, &input)?
+    {
         Resolved::External => return Err(CliError::ExternalMain),
         Resolved::Ignore => return Err(CliError::IgnoredMain),
         Resolved::Normal(path) => path,
     };
 
-    if watch {
-        let progress_line = format!(" build {output} ...", output = output);
-        eprint!("{}", progress_line);
-        io::Write::flush(&mut io::stderr())?;
+    let mut bundler = Bundler::new(
+        &entry_point,
+        input_options.clone(),
+        &output,
+        &map_output,
+        Arc::clone(&cache),
+        jobs,
+    );
+    if let Some(dir) = cache_dir {
+        bundler = bundler.with_disk_cache(Arc::new(DiskCache::new(
+            PathBuf::from(dir),
+            &input_options,
+        )?));
+    }
+
+    let server_options = if serve {
+        bundler = bundler.serve();
+        let map_path = match map_output {
+            SourceMapOutput::File(ref path, _) => Some(path.as_path()),
+            _ => None,
+        };
+        let static_dir = match static_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => env::current_dir()?,
+        };
+        Some((map_path, static_dir))
+    } else {
+        None
+    };
 
-        let mut modules = match bundle(&entry_point, input_options.clone(), &output, &map_output) {
-            Ok(mods) => mods,
-            Err(e) => {
-                eprintln!();
-                return Err(e);
+    if watch || serve {
+        let reporter = match reporter {
+            Some(Reporter::Text { .. }) | None => Reporter::Text { quiet: quiet_watch },
+            Some(reporter) => reporter,
+        };
+        reporter.build_started(&output, true);
+        let initial = match bundler.build(&[]) {
+            // the server shows it in the page, and waits for a fix like any later error
+            Err(error) if serve => Err(error),
+            Err(error) => {
+                let elapsed = entry_inst.elapsed();
+                reporter.build_failed(&output, true, elapsed, &error);
+                run_hook(&mut hooks, &reporter, &output, elapsed, &[], Some(&error));
+                return Err(match reporter {
+                    Reporter::Json => CliError::Reported(Box::new(error)),
+                    Reporter::Text { .. } => error,
+                });
             }
+            result => result,
         };
         let elapsed = entry_inst.elapsed();
-        let ms = elapsed.as_secs() * 1_000 + u64::from(elapsed.subsec_millis());
+        let server = match server_options {
+            Some((map_path, static_dir)) => Some(Server::start(
+                SocketAddr::from(([127, 0, 0, 1], port.unwrap_or(DEFAULT_PORT))),
+                &output,
+                map_path,
+                static_dir,
+                bundler.served().expect("it's serving"),
+            )?),
+            None => None,
+        };
+        // nothing's written in serve mode, so hooks are told where it's served
+        let hook_output = match server {
+            Some(ref server) => server.bundle_url(),
+            None => output.clone(),
+        };
 
         let (tx, rx) = mpsc::channel();
         let debounce_duration = time::Duration::from_millis(5);
-        let mut watcher = notify::raw_watcher(tx.clone())?;
+        // how often a hook that's finished is waited for while nothing changes
+        let reap_interval = time::Duration::from_secs(1);
+        let mut watcher = match watch_poll {
+            Some(interval) => FileWatcher::Poll(Poller::new(tx, interval)),
+            None => FileWatcher::Notify(notify::raw_watcher(tx)?),
+        };
 
-        for path in modules.keys() {
-            watcher.watch(path, notify::RecursiveMode::NonRecursive)?;
+        let watched = Arc::new(RwLock::new(bundler.watched()));
+        let mut targets = watcher.targets(&watched.read().unwrap());
+        for path in &targets {
+            watcher.watch(path)?;
+        }
+        let cancel = bundler.cancel_handle();
+        let (changes_tx, changes) = mpsc::channel();
+        {
+            let watched = Arc::clone(&watched);
+            let cancel = cancel.clone();
+            thread::spawn(move || forward_changes(rx, changes_tx, &watched, &cancel));
         }
 
-        eprintln!(
-            "{bs} ready {output} in {ms} ms",
-            output = output,
-            ms = ms,
-            bs = "\u{8}".repeat(progress_line.len())
-        );
-
-        loop {
-            let first_event = rx.recv().expect("notify::watcher disconnected");
-            thread::sleep(debounce_duration);
-            for event in iter::once(first_event).chain(rx.try_iter()) {
-                let _op = event.op?;
+        match initial {
+            Ok(stats) => {
+                reporter.build_succeeded(
+                    &output,
+                    true,
+                    elapsed,
+                    stats,
+                    output_bytes(&bundler, &output),
+                );
+                if let Some(ref server) = server {
+                    reporter.serving(&server.url());
+                }
+                run_hook(&mut hooks, &reporter, &hook_output, elapsed, &[], None);
+            }
+            Err(error) => {
+                reporter.build_failed(&output, false, elapsed, &error);
+                if let Some(ref server) = server {
+                    server.failed(&error.to_string());
+                    reporter.serving(&server.url());
+                }
+                run_hook(
+                    &mut hooks,
+                    &reporter,
+                    &hook_output,
+                    elapsed,
+                    &[],
+                    Some(&error),
+                );
             }
+        }
 
-            eprint!("update {} ...", output);
-            io::Write::flush(&mut io::stderr())?;
+        loop {
+            let first = loop {
+                match changes.recv_timeout(reap_interval) {
+                    Ok(change) => break change,
+                    Err(mpsc::RecvTimeoutError::Timeout) => hooks.reap(),
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        panic!("notify::watcher disconnected")
+                    }
+                }
+            };
+            let mut changed = vec![first?];
+            // including the changes that cancelled a build, for the hooks
+            let mut all_changed = Vec::new();
             let start_inst = time::Instant::now();
-            match bundle(&entry_point, input_options.clone(), &output, &map_output) {
-                Ok(new_modules) => {
-                    let elapsed = start_inst.elapsed();
-                    let ms = elapsed.as_secs() * 1_000 + u64::from(elapsed.subsec_millis());
-                    eprintln!("{bs}in {ms} ms", ms = ms, bs = "\u{8}".repeat(3));
-
-                    {
-                        let mut to_unwatch = modules.keys().collect::<FnvHashSet<_>>();
-                        let mut to_watch = new_modules.keys().collect::<FnvHashSet<_>>();
-                        for path in modules.keys() {
-                            to_watch.remove(&path);
-                        }
-                        for path in new_modules.keys() {
-                            to_unwatch.remove(&path);
-                        }
-                        for path in to_watch {
-                            watcher.watch(path, notify::RecursiveMode::NonRecursive)?;
-                        }
-                        for path in to_unwatch {
-                            watcher.unwatch(path)?;
-                        }
+            // restarted whenever something changes before it's done
+            let mut restarted = false;
+            let result = loop {
+                thread::sleep(debounce_duration);
+                // anything that changes from now on cancels the build
+                cancel.reset();
+                for path in changes.try_iter() {
+                    let path = path?;
+                    if !changed.contains(&path) {
+                        changed.push(path);
                     }
-                    modules = new_modules;
                 }
-                Err(kind) => {
-                    eprintln!("{}error: {}", if quiet_watch { "" } else { "\x07" }, kind);
+                reporter.changed(&changed);
+                for path in &changed {
+                    if !all_changed.contains(path) {
+                        all_changed.push(path.clone());
+                    }
+                }
+                if restarted {
+                    reporter.build_restarted(&output);
+                } else {
+                    reporter.build_started(&output, false);
+                }
+                match bundler.build(&changed) {
+                    Err(CliError::Cancelled) => {
+                        reporter.build_cancelled();
+                        changed.clear();
+                        restarted = true;
+                    }
+                    result => break result,
+                }
+            };
+            let elapsed = start_inst.elapsed();
+            match result {
+                Ok(stats) => {
+                    reporter.build_succeeded(
+                        &output,
+                        false,
+                        elapsed,
+                        stats,
+                        output_bytes(&bundler, &output),
+                    );
+                    if let Some(ref server) = server {
+                        server.succeeded(bundler.served().expect("the build succeeded"));
+                    }
+                    run_hook(
+                        &mut hooks,
+                        &reporter,
+                        &hook_output,
+                        elapsed,
+                        &all_changed,
+                        None,
+                    );
+                }
+                Err(error) => {
+                    reporter.build_failed(&output, false, elapsed, &error);
+                    if let Some(ref server) = server {
+                        server.failed(&error.to_string());
+                    }
+                    run_hook(
+                        &mut hooks,
+                        &reporter,
+                        &hook_output,
+                        elapsed,
+                        &all_changed,
+                        Some(&error),
+                    );
                 }
             }
+
+            // after an error, this includes the files that would fix it
+            let new_watched = bundler.watched();
+            let new_targets = watcher.targets(&new_watched);
+            *watched.write().unwrap() = new_watched;
+            for path in new_targets.difference(&targets) {
+                watcher.watch(path)?;
+            }
+            for path in targets.difference(&new_targets) {
+                watcher.unwatch(path);
+            }
+            targets = new_targets;
         }
     } else {
-        bundle(&entry_point, input_options, &output, &map_output).map(|_| ())
+        bundler.build(&[]).map(|_| ())
+    }
+}
+
+// reports changes to the files a build depends on, as notify's raw events
+enum FileWatcher {
+    Notify(notify::RecommendedWatcher),
+    Poll(Poller),
+}
+
+impl FileWatcher {
+    // what to watch for changes to `watched`
+    fn targets(&self, watched: &FnvHashSet<PathBuf>) -> FnvHashSet<PathBuf> {
+        match self {
+            FileWatcher::Notify(_) => watch_targets(watched),
+            // which can watch files that don't exist
+            FileWatcher::Poll(_) => watched.clone(),
+        }
+    }
+
+    fn watch(&mut self, path: &Path) -> Result<(), CliError> {
+        match self {
+            FileWatcher::Notify(watcher) => {
+                watcher.watch(path, notify::RecursiveMode::NonRecursive)?
+            }
+            FileWatcher::Poll(poller) => poller.watch(path),
+        }
+        Ok(())
+    }
+
+    fn unwatch(&mut self, path: &Path) {
+        match self {
+            // deleted files aren't watched anymore
+            FileWatcher::Notify(watcher) => drop(watcher.unwatch(path)),
+            FileWatcher::Poll(poller) => poller.unwatch(path),
+        }
+    }
+}
+
+// how big the bundle is, for the json reporter; None when it's written to stdout
+fn output_bytes(bundler: &Bundler, output: &str) -> Option<u64> {
+    match bundler.served() {
+        Some(served) => Some(served.bundle.len() as u64),
+        None if output == "-" => None,
+        None => fs::metadata(output).ok().map(|metadata| metadata.len()),
+    }
+}
+
+fn run_hook(
+    hooks: &mut Hooks,
+    reporter: &Reporter,
+    output: &str,
+    duration: time::Duration,
+    changed: &[PathBuf],
+    error: Option<&CliError>,
+) {
+    let env = HookEnv {
+        output,
+        duration,
+        changed,
+        error: error.map(|error| error.to_string()),
+    };
+    if let Err((command, error)) = hooks.run(env) {
+        reporter.hook_failed(&command, &error);
+    }
+}
+
+fn parse_reporter(opt: String, name: String) -> Result<Reporter, CliError> {
+    name.parse()
+        .map_err(|_| CliError::InvalidOptionValue(opt, name))
+}
+
+// passes on the changes that can affect the next build, cancelling the one in progress
+fn forward_changes(
+    rx: mpsc::Receiver<notify::RawEvent>,
+    tx: mpsc::Sender<Result<PathBuf, notify::Error>>,
+    watched: &RwLock<FnvHashSet<PathBuf>>,
+    cancel: &Cancel,
+) {
+    for event in rx {
+        let change = match (event.op, event.path) {
+            (Err(error), _) => Err(error),
+            (Ok(_), Some(path)) if affects_build(&path, &watched.read().unwrap()) => Ok(path),
+            (Ok(_), _) => continue,
+        };
+        // in the channel before the build is cancelled, so that the reset before the next build
+        // can't clear the cancel without the next build seeing the change
+        if tx.send(change).is_err() {
+            return;
+        }
+        cancel.cancel();
     }
 }
 
+// what to watch for changes to `paths`: each one that exists, and for each one that doesn't, the
+// nearest directory above it that does
+fn watch_targets(paths: &FnvHashSet<PathBuf>) -> FnvHashSet<PathBuf> {
+    paths
+        .iter()
+        .filter_map(|path| path.ancestors().find(|dir| dir.exists()))
+        .map(Path::to_owned)
+        .collect()
+}
+
+// whether a change to `path`, reported by a watch on it or its directory, can affect a build
+// that depends on `watched`: it's one of them, a directory one of them is in, or a manifest
+fn affects_build(path: &Path, watched: &FnvHashSet<PathBuf>) -> bool {
+    manifest::is_manifest(path) || watched.iter().any(|watched| watched.starts_with(path))
+}
+
+// scrumple resolve [options] <from-file> <specifier>
+fn run_resolve<I: Iterator<Item = String>>(mut iter: opts::Expand<I>) -> Result<(), CliError> {
+    let mut from = None;
+    let mut specifier = None;
+    let mut json = false;
+    let mut resolve_options = ResolveOptions::default();
+
+    while let Some(arg) = iter.next() {
+        let opt = match arg {
+            opts::Arg::Pos(arg) => {
+                if from.is_none() {
+                    from = Some(arg)
+                } else if specifier.is_none() {
+                    specifier = Some(arg)
+                } else {
+                    return Err(CliError::UnexpectedArg(arg));
+                }
+                continue;
+            }
+            opts::Arg::Opt(opt) => opt,
+        };
+        match &*opt {
+            "-h" | "--help" => return Err(CliError::Help),
+            "--json" => json = true,
+            _ => {
+                if !resolve_options.parse(opt.clone(), &mut iter)? {
+                    return Err(CliError::UnknownOption(opt));
+                }
+            }
+        }
+    }
+
+    let (from, specifier) = match (from, specifier) {
+        (Some(from), Some(specifier)) => (from, specifier),
+        _ => return Err(CliError::MissingFileName),
+    };
+    let input_dir = env::current_dir()?;
+    let input_options = resolve_options.into_input_options(&from, &input_dir)?;
+    let mut context = input_dir;
+    context.append_resolving(&from);
+
+    let resolver = Resolver::new(input_options).with_trace();
+    let result = resolver.resolve(&context, &specifier);
+    let trace = resolver.take_trace();
+
+    if json {
+        let (resolved, error) = match result {
+            Ok(Resolved::Normal(ref path)) => {
+                (serde_json::json!({ "type": "normal", "path": path }), None)
+            }
+            Ok(Resolved::External) => (serde_json::json!({ "type": "external" }), None),
+            Ok(Resolved::Ignore) => (serde_json::json!({ "type": "ignore" }), None),
+            Err(ref error) => (serde_json::Value::Null, Some(error.to_string())),
+        };
+        let report = serde_json::json!({
+            "from": context,
+            "specifier": specifier,
+            "trace": trace,
+            "resolved": resolved,
+            "error": error,
+        });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        if result.is_err() {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    for step in &trace {
+        println!("{}", step);
+    }
+    match result? {
+        Resolved::Normal(path) => println!("resolved {}", path.display()),
+        Resolved::External => println!("resolved as external"),
+        Resolved::Ignore => println!("resolved as ignored"),
+    }
+    Ok(())
+}
+
 const APP_NAME: &str = env!("CARGO_PKG_NAME");
 const EXE_NAME: &str = "scrumple";
 const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -422,6 +993,8 @@ fn write_usage(f: &mut fmt::Formatter) -> fmt::Result {
         f,
         "\
 Usage: {0} [options] <input> [output]
+       {0} resolve [--json] [options] <from-file> <specifier>
+       {0} serve [options] <input> [path]
        {0} [-h | --help | -v | --version]",
         EXE_NAME
     )
@@ -439,6 +1012,15 @@ fn write_help(f: &mut fmt::Formatter) -> fmt::Result {
     writeln!(
         f,
         "\
+scrumple resolve prints each step of resolving require('<specifier>') in
+<from-file>: aliases and browser field substitutions applied, manifests
+read, and every path tried. It takes the options that affect resolution.
+
+scrumple serve builds <input> and serves it from memory on localhost, at
+<path> (default: bundle.js) with its source map at <path>.map, and other files
+from the current directory. It rebuilds like --watch, and pages that load the
+bundle reload after each rebuild and show build errors over the page.
+
 Options:
     -i, --input <input>
         Use <input> as the main module.
@@ -457,12 +1039,52 @@ Options:
         Suppress source map output when it would normally be implied.
 
     -w, --watch
-        Watch for changes to <input> and its dependencies.
+        Watch for changes to <input> and its dependencies. Rebuilds reuse the
+        modules and resolved requires that the changes don't affect, and start
+        over without writing anything if more changes come in first. After an
+        error, it also watches for the files that a failed require looked for.
 
     -W, --quiet-watch
         Don't emit a bell character for errors that occur while watching.
         Implies --watch.
 
+    --watch-poll[=<ms>]
+        Check for changes every <ms> milliseconds instead of asking the
+        operating system to report them, for network filesystems and
+        container bind mounts where it can't. A file that's touched without
+        changing its contents doesn't trigger a rebuild. Default: 500.
+        Implies --watch.
+
+    --reporter <text|json>
+        How to report progress while watching. `json` writes one JSON object
+        per line to stderr for each change, build start, cancelled build,
+        success and failure, for editors and other tools. Default: text.
+
+    --on-success <cmd>, --on-error <cmd>
+        Run <cmd> with the shell after each build that succeeds or fails
+        while watching, e.g. to start the tests. It's given SCRUMPLE_OUTPUT
+        (with scrumple serve, the bundle's URL), SCRUMPLE_DURATION_MS,
+        SCRUMPLE_CHANGED (the files that changed, one per line) and, after an
+        error, SCRUMPLE_ERROR. A command that's still running from the last
+        build is killed first.
+
+    --port <port>
+        Serve on <port> (scrumple serve only). Default: 8000.
+
+    --static <dir>
+        Serve other files from <dir> (scrumple serve only). Default: the
+        current directory.
+
+    -j, --jobs <n>
+        Resolve and include modules on <n> threads. Default: the number of
+        CPUs.
+
+    --cache-dir <dir>
+        Keep transformed modules and resolved requires in <dir>, and reuse
+        them in later builds. Modules are reused while their contents are
+        unchanged, and requires while the files and manifests they depended
+        on are. The bundle is the same with or without the cache.
+
     -x, --external <module1,module2,...>
         Don't resolve or include modules named <module1>, <module2>, etc.;
         leave them as require('<module>') references in the bundle. Specifying
@@ -475,11 +1097,54 @@ Options:
     -b, --for-bower
         Use bower.json instead of package.json
 
+    --pnp
+        Find npm packages through the Yarn Plug'n'Play data file,
+        .pnp.data.json (written with pnpEnableInlining: false), instead of
+        node_modules. Packages must be unplugged, since zip archives can't be
+        read. Files outside a PnP project still use node_modules.
+
     -N, --allow-npm-dev-deps
         When using --for-bower, this forces packages in the project's
         package.json#devDependencies to be resolved through npm. This is is for
         creating testing bundles that use npm-only dependencies
 
+    --main-fields <field1,field2,...>
+        Use the first of the package.json fields <field1>, <field2>, etc. that
+        a package defines as its entry point. Fields can be browser, module and
+        main. Default: browser,main.
+
+    --resolve-extensions <ext1,ext2,...>
+        Try the extensions <ext1>, <ext2>, etc., in order, when a required
+        path doesn't exist as written. Default: .mjs,.js,.cjs,.json.
+
+    --index-files <file1,file2,...>
+        Try the files <file1>, <file2>, etc., in order, when a required path
+        is a directory. Default: index.mjs,index.js,index.json.
+
+    -a, --alias <from1=to1,from2=to2,...>
+        Resolve requires of <from1> as if they were requires of <to1>, etc.
+        <from> also matches its subpaths ('<from>/...'); end it with '$' to
+        match it exactly, or with '/' to match any require it prefixes.
+        Relative targets are resolved from the current directory.
+
+    --preserve-symlinks
+        Identify modules by the path they were required through instead of
+        the real path of the file, and look up their dependencies from there.
+        By default, a file reached through several symlinks is included once.
+
+    --strict-case
+        Fail when a required path only matches a file because the filesystem
+        ignores case, e.g. require('./Foo') of foo.js on macOS or Windows.
+
+    -c, --config <file>
+        Read options from the JSON file <file>. Options given on the command
+        line take precedence. Keys: mainFields, resolveExtensions, indexFiles,
+        alias (an object mapping <from> to <to>; relative targets are resolved
+        from the directory containing <file>), preserveSymlinks, strictCase.
+
+    --json
+        With resolve: print the trace as JSON.
+
     -h, --help
         Print this message.
 
@@ -499,17 +1164,45 @@ pub enum CliError {
     MissingOptionValue(String),
     UnknownOption(String),
     UnexpectedArg(String),
+    InvalidOptionValue(String, String),
     BadUsage(&'static str),
     RequireRoot {
         context: Option<PathBuf>,
         path: PathBuf,
     },
+    CaseMismatch {
+        context: Option<PathBuf>,
+        path: PathBuf,
+        actual: PathBuf,
+    },
     EmptyModuleName {
         context: PathBuf,
     },
     ModuleNotFound {
         context: PathBuf,
         name: String,
+        // filled in by Resolver::resolve
+        searched: Vec<PathBuf>,
+        tried: Vec<PathBuf>,
+        suggestions: Vec<String>,
+    },
+    AliasCycle {
+        context: PathBuf,
+        chain: Vec<String>,
+    },
+    SubstitutionCycle {
+        context: PathBuf,
+        manifest: PathBuf,
+        chain: Vec<String>,
+    },
+    SubpathNotExported {
+        context: PathBuf,
+        package: String,
+        subpath: String,
+    },
+    ImportNotDefined {
+        context: PathBuf,
+        name: String,
     },
     MainNotFound {
         name: String,
@@ -518,13 +1211,44 @@ pub enum CliError {
         context: PathBuf,
         err: string::FromUtf8Error,
     },
+    InvalidConfig {
+        path: PathBuf,
+        err: serde_json::Error,
+    },
+    InvalidPnpData {
+        path: PathBuf,
+        err: serde_json::Error,
+    },
+    PnpUndeclaredDependency {
+        context: PathBuf,
+        issuer: String,
+        name: String,
+    },
+    PnpMissingPeerDependency {
+        context: PathBuf,
+        issuer: String,
+        name: String,
+    },
+    PnpZipArchive {
+        context: PathBuf,
+        name: String,
+        archive: PathBuf,
+    },
     Io(io::Error),
     Json(serde_json::Error),
     Notify(notify::Error),
     Es6(es6::error::Error),
     Lex(lex::Error),
     ParseStrLit(lex::ParseStrLitError),
-    Box(Box<dyn Any + Send + 'static>),
+    // a bug: a worker thread panicked, while doing something if it's known
+    WorkerPanic {
+        doing: Option<String>,
+        message: String,
+    },
+    // by Cancel::cancel, in watch mode
+    Cancelled,
+    // already reported by the json reporter, so not printed again on the way out
+    Reported(Box<CliError>),
 }
 impl From<io::Error> for CliError {
     fn from(inner: io::Error) -> CliError {
@@ -556,9 +1280,16 @@ impl From<lex::ParseStrLitError> for CliError {
         CliError::ParseStrLit(inner)
     }
 }
-impl From<Box<dyn Any + Send + 'static>> for CliError {
-    fn from(inner: Box<dyn Any + Send + 'static>) -> CliError {
-        CliError::Box(inner)
+
+impl CliError {
+    pub fn module_not_found(context: &Path, name: &str) -> CliError {
+        CliError::ModuleNotFound {
+            context: context.to_owned(),
+            name: name.to_owned(),
+            searched: Vec::new(),
+            tried: Vec::new(),
+            suggestions: Vec::new(),
+        }
     }
 }
 
@@ -578,6 +1309,9 @@ impl fmt::Display for CliError {
             CliError::MissingOptionValue(ref opt) => write!(f, "missing value for option {}", opt),
             CliError::UnknownOption(ref opt) => write!(f, "unknown option {}", opt),
             CliError::UnexpectedArg(ref arg) => write!(f, "unexpected argument {}", arg),
+            CliError::InvalidOptionValue(ref opt, ref value) => {
+                write!(f, "invalid value '{}' for option {}", value, opt)
+            }
             CliError::BadUsage(ref arg) => write!(f, "{}", arg),
 
             CliError::RequireRoot {
@@ -592,19 +1326,139 @@ impl fmt::Display for CliError {
                     context.display(),
                 ),
             },
+            CliError::CaseMismatch {
+                ref context,
+                ref path,
+                ref actual,
+            } => {
+                write!(
+                    f,
+                    "{} doesn't match the case of {} on disk",
+                    path.display(),
+                    actual.display(),
+                )?;
+                match *context {
+                    None => write!(f, " (main module)"),
+                    Some(ref context) => write!(f, ", required in {}", context.display()),
+                }
+            }
             CliError::EmptyModuleName { ref context } => {
                 write!(f, "require('') in {}", context.display())
             }
             CliError::ModuleNotFound {
                 ref context,
                 ref name,
-            } => write!(f, "module '{}' not found in {}", name, context.display(),),
+                ref searched,
+                ref tried,
+                ref suggestions,
+            } => {
+                write!(f, "module '{}' not found in {}", name, context.display())?;
+                if !searched.is_empty() {
+                    write!(f, "\n  searched:")?;
+                    for dir in searched {
+                        write!(f, "\n    {}", dir.display())?;
+                    }
+                }
+                if !tried.is_empty() {
+                    write!(f, "\n  tried:")?;
+                    for path in tried {
+                        write!(f, "\n    {}", path.display())?;
+                    }
+                }
+                if !suggestions.is_empty() {
+                    write!(f, "\n  did you mean:")?;
+                    for suggestion in suggestions {
+                        write!(f, "\n    {}", suggestion)?;
+                    }
+                }
+                Ok(())
+            }
+            CliError::AliasCycle {
+                ref context,
+                ref chain,
+            } => write!(
+                f,
+                "alias cycle {} in {}",
+                chain.join(" -> "),
+                context.display(),
+            ),
+            CliError::SubstitutionCycle {
+                ref context,
+                ref manifest,
+                ref chain,
+            } => write!(
+                f,
+                "browser substitution cycle {} in {}, required in {}",
+                chain.join(" -> "),
+                manifest.display(),
+                context.display(),
+            ),
+            CliError::SubpathNotExported {
+                ref context,
+                ref package,
+                ref subpath,
+            } => write!(
+                f,
+                "subpath '{}' is not exported by package '{}', required in {}",
+                subpath,
+                package,
+                context.display(),
+            ),
+            CliError::ImportNotDefined {
+                ref context,
+                ref name,
+            } => write!(
+                f,
+                "'{}' is not defined in package.json#imports for {}",
+                name,
+                context.display(),
+            ),
             CliError::MainNotFound { ref name } => write!(f, "main module '{}' not found", name),
 
             CliError::InvalidUtf8 {
                 ref context,
                 ref err,
             } => write!(f, "in {}: {}", context.display(), err),
+            CliError::InvalidConfig { ref path, ref err } => {
+                write!(f, "in config {}: {}", path.display(), err)
+            }
+            CliError::InvalidPnpData { ref path, ref err } => {
+                write!(f, "in PnP data {}: {}", path.display(), err)
+            }
+            CliError::PnpUndeclaredDependency {
+                ref context,
+                ref issuer,
+                ref name,
+            } => write!(
+                f,
+                "{} requires '{}', which isn't among its dependencies, in {}",
+                issuer,
+                name,
+                context.display(),
+            ),
+            CliError::PnpMissingPeerDependency {
+                ref context,
+                ref issuer,
+                ref name,
+            } => write!(
+                f,
+                "{} requires its peer dependency '{}', which isn't provided by the package that depends on it, in {}",
+                issuer,
+                name,
+                context.display(),
+            ),
+            CliError::PnpZipArchive {
+                ref context,
+                ref name,
+                ref archive,
+            } => write!(
+                f,
+                "package '{}' is stored in the zip archive {}, which can't be read; unplug it with `yarn unplug {}`, required in {}",
+                name,
+                archive.display(),
+                name,
+                context.display(),
+            ),
 
             CliError::Io(ref inner) => write!(f, "{}", inner),
             CliError::Json(ref inner) => write!(f, "{}", inner),
@@ -612,7 +1466,15 @@ impl fmt::Display for CliError {
             CliError::Es6(ref inner) => write!(f, "{}", inner),
             CliError::Lex(ref inner) => write!(f, "{}", inner),
             CliError::ParseStrLit(ref inner) => write!(f, "{}", inner),
-            CliError::Box(ref inner) => write!(f, "{:?}", inner),
+            CliError::WorkerPanic {
+                ref doing,
+                ref message,
+            } => match *doing {
+                Some(ref doing) => write!(f, "internal error while {}: {}", doing, message),
+                None => write!(f, "internal error in a worker thread: {}", message),
+            },
+            CliError::Cancelled => write!(f, "build cancelled"),
+            CliError::Reported(ref inner) => write!(f, "{}", inner),
         }
     }
 }
@@ -625,6 +1487,7 @@ fn main() {
                 CliError::Help | CliError::Version | CliError::MissingFileName => {
                     println!("{}", kind);
                 }
+                CliError::Reported(_) => {}
                 _ => {
                     println!("{}: {}", EXE_NAME, kind);
                 }
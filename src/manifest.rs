@@ -1,13 +1,16 @@
+use crate::input_options::{MainField, MainFields, PackageManager};
 use crate::path_ext::*;
+use crate::pnp::{self, PnpData};
 use crate::CliError;
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet};
 use matches::matches;
 use serde::de::{SeqAccess, Visitor};
 use serde::{de, Deserialize, Deserializer};
-use std::cell::RefCell;
+use std::ffi::OsString;
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
 use std::{fmt, fs, io, mem};
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -20,21 +23,192 @@ pub enum BrowserSubstitution<T> {
 #[serde(transparent)]
 pub struct BrowserSubstitutionMap(pub FnvHashMap<PathBuf, BrowserSubstitution<PathBuf>>);
 
-#[derive(Debug, Clone, Default)]
+// `import` and `require` are both active: every module ends up wrapped as CommonJS in the
+// bundle, so whichever target the package lists first works
+pub const EXPORTS_CONDITIONS: &[&str] = &["browser", "import", "require", "default"];
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ExportTarget {
+    Null,
+    Path(String),
+    Fallbacks(Vec<ExportTarget>),
+    Conditions(Vec<(String, ExportTarget)>),
+}
+
+// subpath keys ("." or "./…") in the order they appear in package.json
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct SubpathMap(pub Vec<(String, ExportTarget)>);
+
+// a map from paths to values that are computed at most once, however many threads ask at the
+// same time
+#[derive(Debug)]
+pub struct OnceMap<V>(RwLock<FnvHashMap<PathBuf, Arc<OnceLock<V>>>>);
+
+impl<V> Default for OnceMap<V> {
+    fn default() -> Self {
+        OnceMap(RwLock::default())
+    }
+}
+
+impl<V: Clone> OnceMap<V> {
+    fn cell(&self, key: &Path) -> Arc<OnceLock<V>> {
+        if let Some(cell) = self.0.read().unwrap().get(key) {
+            return Arc::clone(cell);
+        }
+        Arc::clone(self.0.write().unwrap().entry(key.to_owned()).or_default())
+    }
+
+    pub fn get_or_init<F: FnOnce() -> V>(&self, key: &Path, init: F) -> V {
+        self.cell(key).get_or_init(init).clone()
+    }
+
+    // errors aren't cached, so a failing `init` runs again next time
+    pub fn get_or_try_init<E, F: FnOnce() -> Result<V, E>>(
+        &self,
+        key: &Path,
+        init: F,
+    ) -> Result<V, E> {
+        let cell = self.cell(key);
+        if let Some(value) = cell.get() {
+            return Ok(value.clone());
+        }
+        let value = init()?;
+        Ok(cell.get_or_init(|| value).clone())
+    }
+
+    #[cfg(test)]
+    pub fn insert(&self, key: &Path, value: V) {
+        self.0
+            .write()
+            .unwrap()
+            .insert(key.to_owned(), Arc::new(OnceLock::from(value)));
+    }
+
+    // removes `path` and everything below it
+    pub fn remove_under(&self, path: &Path) {
+        self.0
+            .write()
+            .unwrap()
+            .retain(|key, _| !key.starts_with(path));
+    }
+
+    pub fn remove(&self, key: &Path) {
+        self.0.write().unwrap().remove(key);
+    }
+
+    pub fn clear(&self) {
+        self.0.write().unwrap().clear();
+    }
+}
+
+// package.json, bower.json and the like, whose changes can affect how any require resolves
+pub fn is_manifest(path: &Path) -> bool {
+    path.file_name().is_some_and(|file_name| {
+        file_name == pnp::DATA_FILE
+            || [PackageManager::Npm, PackageManager::Bower]
+                .iter()
+                .any(|pm| pm.files().iter().any(|file| file_name == *file))
+    })
+}
+
+#[derive(Debug)]
+pub struct Workspace {
+    // package name => package directory
+    pub packages: FnvHashMap<String, PathBuf>,
+    // every directory the globs matched, whether or not it has a manifest
+    pub dirs: Vec<PathBuf>,
+}
+
+// shared by all the workers of a build, and kept between watch mode rebuilds
+#[derive(Debug, Default)]
 pub struct PackageCache {
-    pub pkgs: RefCell<FnvHashMap<PathBuf, Option<Rc<PackageInfo>>>>,
+    pub pkgs: OnceMap<Option<Arc<PackageInfo>>>,
+    // workspace root => its packages
+    pub workspaces: OnceMap<Arc<Workspace>>,
+    // directory => the PnP data file in it
+    pub pnp: OnceMap<Option<Arc<PnpData>>>,
+    // directory => the names in it, for --strict-case
+    pub dir_entries: OnceMap<Option<Arc<Vec<OsString>>>>,
+    pub main_fields: MainFields,
+    // how many manifest files have been read and parsed
+    manifest_reads: AtomicUsize,
 }
 
 impl PackageCache {
+    pub fn new(main_fields: MainFields) -> Self {
+        PackageCache {
+            main_fields,
+            ..Default::default()
+        }
+    }
+
+    #[cfg(test)]
+    pub fn manifest_reads(&self) -> usize {
+        self.manifest_reads.load(Ordering::Relaxed)
+    }
+
+    // forgets what was read from `path`, which has been changed, added or removed
+    pub fn invalidate(&self, path: &Path) {
+        if let (Some(dir), Some(file_name)) = (path.parent(), path.file_name()) {
+            if file_name == pnp::DATA_FILE {
+                self.pnp.remove(dir);
+            } else if is_manifest(path) {
+                self.pkgs.remove(dir);
+                // its name or workspaces may have changed
+                self.workspaces.clear();
+            }
+            self.dir_entries.remove(dir);
+        }
+        self.pkgs.remove_under(path);
+        self.pnp.remove_under(path);
+        self.dir_entries.remove_under(path);
+    }
+
+    // the manifest files read so far, to watch for changes
+    pub fn manifest_files(&self) -> FnvHashSet<PathBuf> {
+        let dirs: Vec<PathBuf> = self
+            .pkgs
+            .0
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, info)| matches!(info.get(), Some(Some(_))))
+            .map(|(dir, _)| dir.clone())
+            .collect();
+        let mut files = FnvHashSet::default();
+        for dir in dirs {
+            for pm in &[PackageManager::Npm, PackageManager::Bower] {
+                for file in pm.files() {
+                    let path = dir.join(file);
+                    if path.is_file() {
+                        files.insert(path);
+                    }
+                }
+            }
+        }
+        files
+    }
+
     pub fn nearest_package_info(
+        &self,
+        dir: PathBuf,
+        package_manager: PackageManager,
+    ) -> Result<Option<Arc<PackageInfo>>, CliError> {
+        Ok(self
+            .nearest_package(dir, package_manager)?
+            .map(|(_, info)| info))
+    }
+
+    // like nearest_package_info, but also returns the package's root directory
+    pub fn nearest_package(
         &self,
         mut dir: PathBuf,
-        package_manager: crate::input_options::PackageManager,
-    ) -> Result<Option<Rc<PackageInfo>>, CliError> {
+        package_manager: PackageManager,
+    ) -> Result<Option<(PathBuf, Arc<PackageInfo>)>, CliError> {
         loop {
             if !matches!(dir.file_name(), Some(s) if s == package_manager.dir()) {
-                if let Some(info) = self.package_info(&mut dir, package_manager)? {
-                    return Ok(Some(info));
+                if let Some(info) = self.package_info(&dir, package_manager)? {
+                    return Ok(Some((dir, info)));
                 }
             }
             if !dir.pop() {
@@ -43,48 +217,145 @@ impl PackageCache {
         }
     }
 
+    // the packages of the workspace root `root`, whose package.json has `globs`
+    pub fn workspace(
+        &self,
+        root: &Path,
+        globs: &[String],
+        package_manager: PackageManager,
+    ) -> Result<Arc<Workspace>, CliError> {
+        self.workspaces.get_or_try_init(root, || {
+            let mut packages = FnvHashMap::default();
+            let dirs = expand_workspace_globs(root, globs);
+            for dir in &dirs {
+                if let Some(info) = self.package_info(dir, package_manager)? {
+                    if let Some(ref name) = info.name {
+                        packages.entry(name.clone()).or_insert_with(|| dir.clone());
+                    }
+                }
+            }
+            Ok(Arc::new(Workspace { packages, dirs }))
+        })
+    }
+
+    // the PnP data of the nearest project above `dir`
+    pub fn pnp_data(&self, mut dir: PathBuf) -> Result<Option<Arc<PnpData>>, CliError> {
+        loop {
+            let data = self.pnp.get_or_try_init(&dir, || {
+                let path = dir.join(pnp::DATA_FILE);
+                Ok::<_, CliError>(if path.is_file() {
+                    Some(Arc::new(PnpData::load(&path)?))
+                } else {
+                    None
+                })
+            })?;
+            if data.is_some() {
+                return Ok(data);
+            }
+            if !dir.pop() {
+                return Ok(None);
+            }
+        }
+    }
+
+    // the names in `dir` as the filesystem stores them, or None if it can't be listed
+    pub fn dir_entries(&self, dir: &Path) -> Option<Arc<Vec<OsString>>> {
+        self.dir_entries.get_or_init(dir, || {
+            fs::read_dir(dir).ok().map(|entries| {
+                Arc::new(
+                    entries
+                        .filter_map(Result::ok)
+                        .map(|entry| entry.file_name())
+                        .collect(),
+                )
+            })
+        })
+    }
+
     pub fn package_info(
         &self,
-        dir: &mut PathBuf,
-        package_manager: crate::input_options::PackageManager,
-    ) -> Result<Option<Rc<PackageInfo>>, CliError> {
-        let mut pkgs = self.pkgs.borrow_mut();
+        dir: &Path,
+        package_manager: PackageManager,
+    ) -> Result<Option<Arc<PackageInfo>>, CliError> {
         let manifest_file_names = package_manager.files();
-        Ok(pkgs
-            .entry(dir.clone())
-            .or_insert_with(|| {
-                for manifest_file_name in manifest_file_names {
-                    dir.push(manifest_file_name);
-                    if let Ok(file) = fs::File::open(&dir) {
-                        let buf_reader = io::BufReader::new(file);
-                        if let Ok(mut info) = serde_json::from_reader::<_, PackageInfo>(buf_reader)
-                        {
-                            dir.pop();
-                            info.set_base(&dir);
-                            return Some(Rc::new(info));
-                        } else {
-                            dir.pop();
-                        }
+        let main_fields = &self.main_fields;
+        Ok(self.pkgs.get_or_init(dir, || {
+            let mut dir = dir.to_owned();
+            for manifest_file_name in manifest_file_names {
+                dir.push(manifest_file_name);
+                if let Ok(file) = fs::File::open(&dir) {
+                    self.manifest_reads.fetch_add(1, Ordering::Relaxed);
+                    let buf_reader = io::BufReader::new(file);
+                    if let Ok(mut info) = serde_json::from_reader::<_, PackageInfo>(buf_reader) {
+                        dir.pop();
+                        info.select_main(&main_fields.0);
+                        info.set_base(&dir);
+                        return Some(Arc::new(info));
                     } else {
                         dir.pop();
                     }
+                } else {
+                    dir.pop();
                 }
-                None
-            })
-            .as_ref()
-            .cloned())
+            }
+            None
+        }))
     }
 }
 
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum PackageType {
+    #[default]
+    CommonJs,
+    Module,
+}
+
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
 pub struct PackageInfo {
     pub main: PathBuf,
+    // the field `main` was taken from, or None for the ./index default
+    pub main_field: Option<MainField>,
+    pub entry_points: FnvHashMap<MainField, PathBuf>,
     pub browser_substitutions: BrowserSubstitutionMap,
+    pub exports: Option<SubpathMap>,
+    pub imports: Option<SubpathMap>,
+    pub package_type: PackageType,
+    pub name: Option<String>,
+    // globs of workspace package directories, relative to this package
+    pub workspaces: Vec<String>,
 }
 
 impl PackageInfo {
+    pub fn select_main(&mut self, main_fields: &[MainField]) {
+        let main = self
+            .entry_points
+            .get(&MainField::Main)
+            .cloned()
+            .unwrap_or_else(|| PathBuf::from("./index"));
+        self.main_field = main_fields
+            .iter()
+            .cloned()
+            .find(|field| self.entry_points.contains_key(field));
+        match self.main_field {
+            Some(MainField::Browser) => {
+                // the string form of browser replaces the package's main module
+                let to = self.entry_points[&MainField::Browser].clone();
+                self.browser_substitutions.0.extend(vec![
+                    (PathBuf::from("."), BrowserSubstitution::Replace(to.clone())),
+                    (main.clone(), BrowserSubstitution::Replace(to)),
+                ]);
+                self.main = main;
+            }
+            Some(field) => self.main = self.entry_points[&field].clone(),
+            None => self.main = PathBuf::from("./index"),
+        }
+    }
+
     fn set_base(&mut self, base: &Path) {
         self.main.prepend_resolving(base);
+        for path in self.entry_points.values_mut() {
+            path.prepend_resolving(base);
+        }
         let substs = mem::replace(&mut self.browser_substitutions, Default::default());
         self.browser_substitutions
             .0
@@ -111,18 +382,206 @@ impl<'de> Deserialize<'de> for PackageInfo {
         struct RawPackageInfo {
             #[serde(deserialize_with = "from_main")]
             main: Option<PathBuf>,
+            #[serde(deserialize_with = "lenient")]
+            module: Option<PathBuf>,
             browser: BrowserField,
+            exports: Option<ExportTarget>,
+            imports: Option<ExportTarget>,
+            #[serde(rename = "type", deserialize_with = "lenient")]
+            package_type: Option<String>,
+            #[serde(deserialize_with = "lenient")]
+            name: Option<String>,
+            #[serde(deserialize_with = "lenient")]
+            workspaces: Option<WorkspacesField>,
+        }
+        // npm takes a list of globs, yarn also takes {"packages": [...], "nohoist": [...]}
+        #[derive(Debug, PartialEq, Eq, Deserialize)]
+        #[serde(untagged)]
+        enum WorkspacesField {
+            Globs(Vec<String>),
+            Object { packages: Vec<String> },
+        }
+        // only accept objects; derived struct impls would also take an array of field values
+        struct RawPackageInfoVisitor;
+
+        impl<'de> Visitor<'de> for RawPackageInfoVisitor {
+            type Value = RawPackageInfo;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "package manifest object")
+            }
+            fn visit_map<A: de::MapAccess<'de>>(self, access: A) -> Result<Self::Value, A::Error> {
+                Deserialize::deserialize(de::value::MapAccessDeserializer::new(access))
+            }
         }
-        let info = RawPackageInfo::deserialize(deserializer)?;
-        let main = info.main.unwrap_or(PathBuf::from("./index"));
-        let browser_substitutions = info.browser.to_map(&main);
+
+        let info = deserializer.deserialize_map(RawPackageInfoVisitor)?;
+        let mut entry_points = FnvHashMap::default();
+        let browser_substitutions = match info.browser {
+            BrowserField::Empty => Default::default(),
+            BrowserField::Main(mut to) => {
+                if !to.is_explicitly_relative() {
+                    to.prepend_resolving(Path::new("."));
+                }
+                entry_points.insert(MainField::Browser, to);
+                Default::default()
+            }
+            BrowserField::Complex(map) => map,
+        };
+        if let Some(module) = info.module {
+            entry_points.insert(MainField::Module, module);
+        }
+        if let Some(main) = info.main {
+            entry_points.insert(MainField::Main, main);
+        }
+        // select_main() picks the real entry point once the main fields are known
         Ok(PackageInfo {
-            main,
+            main: PathBuf::from("./index"),
+            main_field: None,
+            entry_points,
             browser_substitutions,
+            exports: info.exports.map(SubpathMap::from_exports),
+            imports: info.imports.and_then(SubpathMap::from_imports),
+            package_type: match info.package_type.as_deref() {
+                Some("module") => PackageType::Module,
+                _ => PackageType::CommonJs,
+            },
+            name: info.name,
+            workspaces: match info.workspaces {
+                Some(WorkspacesField::Globs(globs)) => globs,
+                Some(WorkspacesField::Object { packages }) => packages,
+                None => Vec::new(),
+            },
         })
     }
 }
 
+impl SubpathMap {
+    fn from_exports(exports: ExportTarget) -> Self {
+        match exports {
+            ExportTarget::Conditions(entries)
+                if entries.iter().any(|(key, _)| key.starts_with('.')) =>
+            {
+                SubpathMap(entries)
+            }
+            // a bare string, array or conditions object is sugar for {".": …}
+            target => SubpathMap(vec![(".".to_owned(), target)]),
+        }
+    }
+
+    fn from_imports(imports: ExportTarget) -> Option<Self> {
+        match imports {
+            ExportTarget::Conditions(entries) => Some(SubpathMap(entries)),
+            _ => None,
+        }
+    }
+
+    // `subpath` is "." or "./…"; None means the subpath is not exported
+    pub fn resolve_export(&self, subpath: &str, conditions: &[&str]) -> Option<String> {
+        self.lookup(subpath, conditions, false)
+    }
+
+    // `specifier` is "#…"; unlike exports, targets may also name another package
+    pub fn resolve_import(&self, specifier: &str, conditions: &[&str]) -> Option<String> {
+        self.lookup(specifier, conditions, true)
+    }
+
+    fn lookup(&self, subpath: &str, conditions: &[&str], allow_bare: bool) -> Option<String> {
+        if let Some((_, target)) = self
+            .0
+            .iter()
+            .find(|(key, _)| key == subpath && !key.contains('*'))
+        {
+            return target.resolve(None, conditions, allow_bare);
+        }
+
+        let mut best: Option<(&str, &ExportTarget, &str)> = None;
+        for (key, target) in &self.0 {
+            let star = match key.find('*') {
+                Some(star) => star,
+                None => continue,
+            };
+            let (prefix, suffix) = (&key[..star], &key[star + 1..]);
+            if subpath.len() < key.len()
+                || !subpath.starts_with(prefix)
+                || !subpath.ends_with(suffix)
+            {
+                continue;
+            }
+            let is_better = match best {
+                None => true,
+                Some((best_key, ..)) => {
+                    let best_star = best_key.find('*').unwrap();
+                    // longest prefix wins, then longest key
+                    star > best_star || (star == best_star && key.len() > best_key.len())
+                }
+            };
+            if is_better {
+                best = Some((key, target, &subpath[star..subpath.len() - suffix.len()]));
+            }
+        }
+        best.and_then(|(_, target, matched)| target.resolve(Some(matched), conditions, allow_bare))
+    }
+}
+
+impl ExportTarget {
+    fn resolve(
+        &self,
+        pattern_match: Option<&str>,
+        conditions: &[&str],
+        allow_bare: bool,
+    ) -> Option<String> {
+        match *self {
+            ExportTarget::Null => None,
+            ExportTarget::Path(ref target) if allow_bare && is_bare_target(target) => {
+                Some(match pattern_match {
+                    Some(matched) => target.replace('*', matched),
+                    None => target.clone(),
+                })
+            }
+            ExportTarget::Path(ref target) => {
+                // targets must stay inside the package, and so must what's substituted into them
+                if !target.starts_with("./")
+                    || has_invalid_segment(&target[2..])
+                    || pattern_match.is_some_and(has_invalid_segment)
+                {
+                    return None;
+                }
+                Some(match pattern_match {
+                    Some(matched) => target.replace('*', matched),
+                    None => target.clone(),
+                })
+            }
+            ExportTarget::Fallbacks(ref targets) => targets
+                .iter()
+                .find_map(|target| target.resolve(pattern_match, conditions, allow_bare)),
+            ExportTarget::Conditions(ref entries) => {
+                for (condition, target) in entries {
+                    if condition == "default" || conditions.contains(&condition.as_str()) {
+                        match *target {
+                            // an explicit null stops the search
+                            ExportTarget::Null => return None,
+                            _ => {
+                                if let Some(path) =
+                                    target.resolve(pattern_match, conditions, allow_bare)
+                                {
+                                    return Some(path);
+                                }
+                            }
+                        }
+                    }
+                }
+                None
+            }
+        }
+    }
+}
+
+fn has_invalid_segment(path: &str) -> bool {
+    path.split(['/', '\\'])
+        .any(|part| matches!(part, "" | "." | ".." | "node_modules"))
+}
+
 #[macro_export]
 macro_rules! map {
     {} => {
@@ -213,23 +672,6 @@ enum BrowserField {
     Complex(BrowserSubstitutionMap),
 }
 
-impl BrowserField {
-    fn to_map(self, main: &Path) -> BrowserSubstitutionMap {
-        match self {
-            BrowserField::Empty => Default::default(),
-            BrowserField::Main(mut to) => {
-                if !to.is_explicitly_relative() {
-                    to.prepend_resolving(Path::new("."));
-                }
-                BrowserSubstitutionMap(map! {
-                    PathBuf::from(".") => BrowserSubstitution::Replace(to.clone()),
-                    main.to_owned() => BrowserSubstitution::Replace(to.clone()),
-                })
-            }
-            BrowserField::Complex(map) => map,
-        }
-    }
-}
 impl Default for BrowserField {
     fn default() -> Self {
         BrowserField::Empty
@@ -264,6 +706,153 @@ impl<'de> Deserialize<'de> for BrowserField {
     }
 }
 
+fn is_bare_target(target: &str) -> bool {
+    !target.is_empty() && !target.starts_with(|c| matches!(c, '.' | '/' | '#'))
+}
+
+impl<'de> Deserialize<'de> for ExportTarget {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ExportTargetVisitor;
+
+        impl<'de> Visitor<'de> for ExportTargetVisitor {
+            type Value = ExportTarget;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "export target path, array, conditions object or null")
+            }
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(ExportTarget::Path(v.to_owned()))
+            }
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut targets = Vec::new();
+                while let Some(target) = seq.next_element()? {
+                    targets.push(target);
+                }
+                Ok(ExportTarget::Fallbacks(targets))
+            }
+            // keeps keys in source order, which decides which condition wins
+            fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut entries = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(ExportTarget::Conditions(entries))
+            }
+
+            visit_unconditionally!('de ExportTarget::Null, bool i64 i128 u64 u128 f64 bytes none some unit newtype_struct enum);
+        }
+
+        deserializer.deserialize_any(ExportTargetVisitor)
+    }
+}
+
+// fields of the wrong type are ignored rather than making the whole manifest invalid
+fn lenient<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Lenient<T> {
+        Valid(T),
+        Invalid(de::IgnoredAny),
+    }
+    Ok(match Lenient::deserialize(deserializer)? {
+        Lenient::Valid(value) => Some(value),
+        Lenient::Invalid(_) => None,
+    })
+}
+
+// workspace globs support *, ? and ** segments, and exclusions starting with !
+fn expand_workspace_globs(root: &Path, globs: &[String]) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    for glob in globs.iter().filter(|glob| !glob.starts_with('!')) {
+        for dir in expand_glob(root, glob) {
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+    }
+    for glob in globs.iter().filter_map(|glob| glob.strip_prefix('!')) {
+        let excluded = expand_glob(root, glob);
+        dirs.retain(|dir| !excluded.contains(dir));
+    }
+    dirs
+}
+
+fn expand_glob(root: &Path, glob: &str) -> Vec<PathBuf> {
+    fn subdirs(dir: &Path) -> Vec<PathBuf> {
+        let mut dirs: Vec<_> = fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .filter_map(Result::ok)
+            .filter(|entry| {
+                let name = entry.file_name();
+                name != "node_modules"
+                    && !name.to_string_lossy().starts_with('.')
+                    && entry.file_type().is_ok_and(|t| t.is_dir())
+            })
+            .map(|entry| entry.path())
+            .collect();
+        dirs.sort();
+        dirs
+    }
+    fn descendants(dir: PathBuf, out: &mut Vec<PathBuf>) {
+        let children = subdirs(&dir);
+        out.push(dir);
+        for child in children {
+            descendants(child, out);
+        }
+    }
+
+    let mut dirs = vec![root.to_owned()];
+    for segment in glob.split('/').filter(|s| !s.is_empty() && *s != ".") {
+        let mut matches = Vec::new();
+        for dir in dirs {
+            if segment == "**" {
+                descendants(dir, &mut matches);
+            } else if segment.contains(['*', '?']) {
+                matches.extend(subdirs(&dir).into_iter().filter(|sub| {
+                    let name = sub.file_name().unwrap().to_string_lossy();
+                    wildcard_matches(segment, &name)
+                }));
+            } else {
+                let sub = dir.join(segment);
+                if sub.is_dir() {
+                    matches.push(sub);
+                }
+            }
+        }
+        dirs = matches;
+    }
+    dirs
+}
+
+fn wildcard_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // backtrack to just after the last * when a match fails
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
 // The main might be a string or an array of strings
 fn from_main<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
 where
@@ -427,7 +1016,12 @@ mod test {
 
     #[test]
     fn test_deserialize_package_info() {
-        let parse = serde_json::from_str::<PackageInfo>;
+        let parse = |s| {
+            serde_json::from_str::<PackageInfo>(s).map(|mut info| {
+                info.select_main(&MainFields::default().0);
+                info
+            })
+        };
         assert_matches!(parse("null"), Err(_));
         assert_matches!(parse("100"), Err(_));
         assert_matches!(parse("[1, 2, 3]"), Err(_));
@@ -435,41 +1029,284 @@ mod test {
             parse(r#"{}"#).unwrap(),
             PackageInfo {
                 main: PathBuf::from("./index"),
+                main_field: None,
+                entry_points: map! {},
                 browser_substitutions: BrowserSubstitutionMap(map! {}),
+                exports: None,
+                imports: None,
+                package_type: PackageType::CommonJs,
+                name: None,
+                workspaces: vec![],
             }
         );
         assert_eq!(
             parse(r#"{"browser": null}"#).unwrap(),
             PackageInfo {
                 main: PathBuf::from("./index"),
+                main_field: None,
+                entry_points: map! {},
                 browser_substitutions: BrowserSubstitutionMap(map! {}),
+                exports: None,
+                imports: None,
+                package_type: PackageType::CommonJs,
+                name: None,
+                workspaces: vec![],
             }
         );
         assert_eq!(
             parse(r#"{"browser": "simple"}"#).unwrap(),
             PackageInfo {
                 main: PathBuf::from("./index"),
+                main_field: Some(MainField::Browser),
+                entry_points: map! {
+                    MainField::Browser => PathBuf::from("./simple"),
+                },
                 browser_substitutions: BrowserSubstitutionMap(map! {
                     PathBuf::from(".") => BrowserSubstitution::Replace(PathBuf::from("./simple")),
                     PathBuf::from("./index") => BrowserSubstitution::Replace(PathBuf::from("./simple")),
                 }),
+                exports: None,
+                imports: None,
+                package_type: PackageType::CommonJs,
+                name: None,
+                workspaces: vec![],
             }
         );
         assert_eq!(
             parse(r#"{"browser": {}}"#).unwrap(),
             PackageInfo {
                 main: PathBuf::from("./index"),
+                main_field: None,
+                entry_points: map! {},
                 browser_substitutions: BrowserSubstitutionMap(map! {}),
+                exports: None,
+                imports: None,
+                package_type: PackageType::CommonJs,
+                name: None,
+                workspaces: vec![],
             }
         );
         assert_eq!(
             parse(r#"{"browser": {"mod": false}}"#).unwrap(),
             PackageInfo {
                 main: PathBuf::from("./index"),
+                main_field: None,
+                entry_points: map! {},
                 browser_substitutions: BrowserSubstitutionMap(map! {
                     PathBuf::from("mod") => BrowserSubstitution::Ignore,
                 }),
+                exports: None,
+                imports: None,
+                package_type: PackageType::CommonJs,
+                name: None,
+                workspaces: vec![],
             }
         );
     }
+
+    #[test]
+    fn test_select_main() {
+        let info = |fields: &[MainField]| {
+            let mut info = serde_json::from_str::<PackageInfo>(
+                r#"{"main": "./main.js", "module": "./module.mjs", "browser": "./browser.js"}"#,
+            )
+            .unwrap();
+            info.select_main(fields);
+            info
+        };
+
+        let browser = info(&[MainField::Browser, MainField::Module, MainField::Main]);
+        assert_eq!(browser.main_field, Some(MainField::Browser));
+        assert_eq!(
+            browser.browser_substitutions,
+            BrowserSubstitutionMap(map! {
+                PathBuf::from(".") => BrowserSubstitution::Replace(PathBuf::from("./browser.js")),
+                PathBuf::from("./main.js") => BrowserSubstitution::Replace(PathBuf::from("./browser.js")),
+            })
+        );
+
+        let module = info(&[MainField::Module, MainField::Browser]);
+        assert_eq!(module.main_field, Some(MainField::Module));
+        assert_eq!(module.main, PathBuf::from("./module.mjs"));
+        assert_eq!(
+            module.browser_substitutions,
+            BrowserSubstitutionMap(map! {})
+        );
+
+        let main = info(&[MainField::Main]);
+        assert_eq!(main.main_field, Some(MainField::Main));
+        assert_eq!(main.main, PathBuf::from("./main.js"));
+
+        let none = info(&[]);
+        assert_eq!(none.main_field, None);
+        assert_eq!(none.main, PathBuf::from("./index"));
+
+        // a malformed field is skipped over like a missing one
+        let mut malformed =
+            serde_json::from_str::<PackageInfo>(r#"{"main": "./main.js", "module": false}"#)
+                .unwrap();
+        malformed.select_main(&[MainField::Module, MainField::Main]);
+        assert_eq!(malformed.main_field, Some(MainField::Main));
+        assert_eq!(malformed.main, PathBuf::from("./main.js"));
+    }
+
+    #[test]
+    fn test_deserialize_exports() {
+        let parse = |s| serde_json::from_str::<PackageInfo>(s).unwrap().exports;
+        assert_eq!(parse(r#"{}"#), None);
+        assert_eq!(parse(r#"{"exports": null}"#), None);
+        assert_eq!(
+            parse(r#"{"exports": "./main.js"}"#),
+            Some(SubpathMap(vec![(
+                ".".to_owned(),
+                ExportTarget::Path("./main.js".to_owned())
+            )]))
+        );
+        assert_eq!(
+            parse(r#"{"exports": {"require": "./a.js", "import": "./b.mjs"}}"#),
+            Some(SubpathMap(vec![(
+                ".".to_owned(),
+                ExportTarget::Conditions(vec![
+                    (
+                        "require".to_owned(),
+                        ExportTarget::Path("./a.js".to_owned())
+                    ),
+                    (
+                        "import".to_owned(),
+                        ExportTarget::Path("./b.mjs".to_owned())
+                    ),
+                ])
+            )]))
+        );
+        assert_eq!(
+            parse(r#"{"exports": {".": ["./a.js", null], "./x/*": null}}"#),
+            Some(SubpathMap(vec![
+                (
+                    ".".to_owned(),
+                    ExportTarget::Fallbacks(vec![
+                        ExportTarget::Path("./a.js".to_owned()),
+                        ExportTarget::Null,
+                    ])
+                ),
+                ("./x/*".to_owned(), ExportTarget::Null),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_resolve_subpath_map() {
+        let map = serde_json::from_str::<PackageInfo>(
+            r#"{"exports": {
+                ".": {"node": "./node.js", "default": "./index.js"},
+                "./a/*": "./lib/a/*.js",
+                "./a/b/*": "./lib/b/*.js",
+                "./a/b/*.css": "./css/*.css",
+                "./hidden/*": null,
+                "./bad": "lib/bad.js",
+                "./escape": "./lib/../../escape.js"
+            }}"#,
+        )
+        .unwrap()
+        .exports
+        .unwrap();
+        let resolve = |subpath| map.resolve_export(subpath, EXPORTS_CONDITIONS);
+        assert_eq!(resolve("."), Some("./index.js".to_owned()));
+        assert_eq!(resolve("./a/x"), Some("./lib/a/x.js".to_owned()));
+        assert_eq!(resolve("./a/b/x"), Some("./lib/b/x.js".to_owned()));
+        assert_eq!(resolve("./a/b/x.css"), Some("./css/x.css".to_owned()));
+        assert_eq!(resolve("./a/"), None);
+        assert_eq!(resolve("./hidden/x"), None);
+        assert_eq!(resolve("./bad"), None);
+        assert_eq!(resolve("./escape"), None);
+        assert_eq!(resolve("./missing"), None);
+        // nor can what the * matches leave the package
+        assert_eq!(resolve("./a/../../../x"), None);
+        assert_eq!(resolve("./a/x/../../y"), None);
+        assert_eq!(resolve("./a//etc/passwd"), None);
+        assert_eq!(resolve("./a/x\\..\\..\\y"), None);
+        assert_eq!(resolve("./a/node_modules/x"), None);
+    }
+
+    #[test]
+    fn test_resolve_imports() {
+        let map = serde_json::from_str::<PackageInfo>(
+            r##"{"imports": {
+                "#utils": "./src/utils.js",
+                "#env": {"node": "./env-node.js", "default": "./env.js"},
+                "#lib/*": "./lib/*.js",
+                "#dep": "some-package",
+                "#dep/*": "@scope/pkg/*.js",
+                "#loop": "#utils",
+                "#abs": "/etc/passwd"
+            }}"##,
+        )
+        .unwrap()
+        .imports
+        .unwrap();
+        let resolve = |specifier| map.resolve_import(specifier, EXPORTS_CONDITIONS);
+        assert_eq!(resolve("#utils"), Some("./src/utils.js".to_owned()));
+        assert_eq!(resolve("#env"), Some("./env.js".to_owned()));
+        assert_eq!(resolve("#lib/a/b"), Some("./lib/a/b.js".to_owned()));
+        assert_eq!(resolve("#dep"), Some("some-package".to_owned()));
+        assert_eq!(resolve("#dep/x"), Some("@scope/pkg/x.js".to_owned()));
+        assert_eq!(resolve("#loop"), None);
+        assert_eq!(resolve("#abs"), None);
+        assert_eq!(resolve("#lib/../../x"), None);
+        assert_eq!(resolve("#missing"), None);
+
+        let parse = |s| serde_json::from_str::<PackageInfo>(s).unwrap().imports;
+        assert_eq!(parse(r#"{"imports": "./a.js"}"#), None);
+    }
+
+    #[test]
+    fn test_deserialize_package_type() {
+        let parse = |s| serde_json::from_str::<PackageInfo>(s).unwrap().package_type;
+        assert_eq!(parse(r#"{}"#), PackageType::CommonJs);
+        assert_eq!(parse(r#"{"type": "commonjs"}"#), PackageType::CommonJs);
+        assert_eq!(parse(r#"{"type": "module"}"#), PackageType::Module);
+        assert_eq!(
+            parse(r#"{"type": "something-else"}"#),
+            PackageType::CommonJs
+        );
+        assert_eq!(parse(r#"{"type": 1}"#), PackageType::CommonJs);
+    }
+
+    #[test]
+    fn test_deserialize_workspaces() {
+        let parse = |s| {
+            let info = serde_json::from_str::<PackageInfo>(s).unwrap();
+            (info.name, info.workspaces)
+        };
+        assert_eq!(parse(r#"{}"#), (None, vec![]));
+        assert_eq!(
+            parse(r#"{"name": "root", "workspaces": ["packages/*", "!packages/x"]}"#),
+            (
+                Some("root".to_owned()),
+                vec!["packages/*".to_owned(), "!packages/x".to_owned()]
+            )
+        );
+        assert_eq!(
+            parse(r#"{"workspaces": {"packages": ["packages/*"], "nohoist": ["**/x"]}}"#),
+            (None, vec!["packages/*".to_owned()])
+        );
+        // a malformed name or workspaces field doesn't invalidate the package
+        assert_eq!(
+            parse(r#"{"name": 1, "workspaces": "packages/*"}"#),
+            (None, vec![])
+        );
+    }
+
+    #[test]
+    fn test_wildcard_matches() {
+        assert!(wildcard_matches("*", "anything"));
+        assert!(wildcard_matches("pkg-*", "pkg-a"));
+        assert!(wildcard_matches("pkg-*", "pkg-"));
+        assert!(!wildcard_matches("pkg-*", "other"));
+        assert!(wildcard_matches("*-util?", "string-utils"));
+        assert!(!wildcard_matches("*-util?", "string-util"));
+        assert!(wildcard_matches("a*b*c", "aXbYbZc"));
+        assert!(!wildcard_matches("a*b*c", "aXbYbZ"));
+        assert!(wildcard_matches("exact", "exact"));
+        assert!(!wildcard_matches("exact", "exactly"));
+    }
 }
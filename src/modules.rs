@@ -1,5 +1,6 @@
 use crate::resolver::Resolved;
 use fnv::FnvHashMap;
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub struct Source {
@@ -11,13 +12,14 @@ pub struct Source {
 
 #[derive(Debug)]
 pub struct ModuleInfo {
-    pub source: Source,
+    // shared with the next build in watch mode, if the module doesn't change
+    pub source: Arc<Source>,
     pub deps: Vec<String>,
 }
 
 #[derive(Debug)]
 pub struct Module {
-    pub source: Source,
+    pub source: Arc<Source>,
     pub deps: FnvHashMap<String, Resolved>,
 }
 
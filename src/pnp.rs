@@ -0,0 +1,215 @@
+use crate::path_ext::*;
+use crate::CliError;
+use fnv::FnvHashMap;
+use regex::Regex;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::{cmp, fs, io};
+
+pub const DATA_FILE: &str = ".pnp.data.json";
+
+// a package in the dependency tree: its name and reference are both null for the top level
+pub type Locator = (Option<String>, Option<String>);
+
+#[derive(Debug)]
+struct PnpPackage {
+    location: PathBuf,
+    // a null target is an unmet peer dependency
+    dependencies: FnvHashMap<String, Option<Locator>>,
+}
+
+// the dependency tree Yarn Plug'n'Play writes to .pnp.data.json (with pnpEnableInlining: false)
+#[derive(Debug)]
+pub struct PnpData {
+    packages: FnvHashMap<Locator, PnpPackage>,
+    // package locations, longest first
+    locations: Vec<(PathBuf, Locator)>,
+    top_level_fallback: bool,
+    fallback_pool: FnvHashMap<String, Option<Locator>>,
+    fallback_exclusions: Vec<Locator>,
+    ignore_pattern: Option<Regex>,
+    root: PathBuf,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PnpResolution {
+    // the issuer isn't part of the dependency tree
+    Outside,
+    Package(PathBuf),
+    Undeclared { issuer: String },
+    MissingPeer { issuer: String },
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawPnpData {
+    #[serde(default)]
+    enable_top_level_fallback: bool,
+    #[serde(default)]
+    fallback_pool: Vec<(String, RawDependency)>,
+    #[serde(default)]
+    fallback_exclusion_list: Vec<(String, Vec<String>)>,
+    #[serde(default)]
+    ignore_pattern_data: Option<String>,
+    package_registry_data: Vec<RegistryEntry>,
+}
+
+// [name, [[reference, package], ...]]
+type RegistryEntry = (Option<String>, Vec<(Option<String>, RawPackage)>);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawPackage {
+    package_location: String,
+    #[serde(default)]
+    package_dependencies: Vec<(String, Option<RawDependency>)>,
+}
+
+// a reference to the package of the same name, or [name, reference] for an aliased package
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawDependency {
+    Reference(String),
+    Alias(String, String),
+}
+
+impl RawDependency {
+    fn into_locator(self, name: &str) -> Locator {
+        match self {
+            RawDependency::Reference(reference) => (Some(name.to_owned()), Some(reference)),
+            RawDependency::Alias(name, reference) => (Some(name), Some(reference)),
+        }
+    }
+}
+
+impl PnpData {
+    pub fn load(path: &Path) -> Result<PnpData, CliError> {
+        let file = fs::File::open(path)?;
+        let raw: RawPnpData = serde_json::from_reader(io::BufReader::new(file)).map_err(|err| {
+            CliError::InvalidPnpData {
+                path: path.to_owned(),
+                err,
+            }
+        })?;
+        Ok(PnpData::from_raw(raw, path.parent().unwrap()))
+    }
+
+    fn from_raw(raw: RawPnpData, root: &Path) -> PnpData {
+        let mut packages = FnvHashMap::default();
+        let mut locations = Vec::new();
+        for (name, versions) in raw.package_registry_data {
+            for (reference, package) in versions {
+                let locator = (name.clone(), reference);
+                let mut location = root.to_owned();
+                location.append_resolving(&package.package_location);
+                locations.push((location.clone(), locator.clone()));
+                let dependencies = package
+                    .package_dependencies
+                    .into_iter()
+                    .map(|(name, dep)| {
+                        let locator = dep.map(|dep| dep.into_locator(&name));
+                        (name, locator)
+                    })
+                    .collect();
+                packages.insert(
+                    locator,
+                    PnpPackage {
+                        location,
+                        dependencies,
+                    },
+                );
+            }
+        }
+        locations.sort_by_key(|(location, _)| cmp::Reverse(location.as_os_str().len()));
+
+        PnpData {
+            packages,
+            locations,
+            top_level_fallback: raw.enable_top_level_fallback,
+            fallback_pool: raw
+                .fallback_pool
+                .into_iter()
+                .map(|(name, dep)| {
+                    let locator = dep.into_locator(&name);
+                    (name, Some(locator))
+                })
+                .collect(),
+            fallback_exclusions: raw
+                .fallback_exclusion_list
+                .into_iter()
+                .flat_map(|(name, references)| {
+                    references
+                        .into_iter()
+                        .map(move |reference| (Some(name.clone()), Some(reference)))
+                })
+                .collect(),
+            ignore_pattern: raw
+                .ignore_pattern_data
+                .and_then(|pattern| Regex::new(&pattern).ok()),
+            root: root.to_owned(),
+        }
+    }
+
+    // finds the directory of the package `name` as required by the file `issuer`
+    pub fn resolve(&self, issuer: &Path, name: &str) -> PnpResolution {
+        let issuer_locator = match self.find_locator(issuer) {
+            Some(locator) => locator,
+            None => return PnpResolution::Outside,
+        };
+        let issuer_package = &self.packages[issuer_locator];
+
+        let dependency = issuer_package.dependencies.get(name).or_else(|| {
+            if !self.top_level_fallback || self.fallback_exclusions.contains(issuer_locator) {
+                return None;
+            }
+            self.packages
+                .get(&(None, None))
+                .and_then(|top_level| top_level.dependencies.get(name))
+                .or_else(|| self.fallback_pool.get(name))
+        });
+
+        match dependency {
+            Some(Some(locator)) => match self.packages.get(locator) {
+                Some(package) => PnpResolution::Package(package.location.clone()),
+                None => PnpResolution::Undeclared {
+                    issuer: display_locator(issuer_locator),
+                },
+            },
+            Some(None) => PnpResolution::MissingPeer {
+                issuer: display_locator(issuer_locator),
+            },
+            None => PnpResolution::Undeclared {
+                issuer: display_locator(issuer_locator),
+            },
+        }
+    }
+
+    fn find_locator(&self, path: &Path) -> Option<&Locator> {
+        if let Some(ref pattern) = self.ignore_pattern {
+            if let Some(relative) = path.relative_from(&self.root) {
+                if pattern.is_match(&relative.to_string_lossy()) {
+                    return None;
+                }
+            }
+        }
+        self.locations
+            .iter()
+            .find(|(location, _)| path.starts_with(location))
+            .map(|(_, locator)| locator)
+    }
+}
+
+fn display_locator(locator: &Locator) -> String {
+    match locator {
+        (Some(name), _) => name.clone(),
+        (None, _) => "the top-level package".to_owned(),
+    }
+}
+
+// yarn keeps packages in zip archives unless they're unplugged
+pub fn zip_archive(location: &Path) -> Option<PathBuf> {
+    location
+        .ancestors()
+        .find(|dir| dir.extension().is_some_and(|ext| ext == "zip"))
+        .map(Path::to_owned)
+}
@@ -0,0 +1,179 @@
+use fnv::{FnvHashMap, FnvHasher};
+use notify::{op, Op, RawEvent};
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use std::{fs, thread};
+
+// watches files by checking on them every `interval`, for filesystems that don't report changes
+// (network shares, container bind mounts). unlike notify's watchers it can watch files that don't
+// exist yet, and it only reports a file when it appears, disappears or its contents change:
+// touching it doesn't count.
+#[derive(Debug)]
+pub struct Poller {
+    files: Arc<Mutex<FnvHashMap<PathBuf, Option<Stat>>>>,
+    quit: Arc<AtomicBool>,
+}
+
+// what's compared between checks; the contents are only read again when the modification time
+// or size changes
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Stat {
+    modified: Option<SystemTime>,
+    len: u64,
+    // of the contents of a file; 0 for anything else
+    hash: u64,
+}
+
+impl Stat {
+    fn of(path: &Path, previous: Option<&Stat>) -> Option<Stat> {
+        let metadata = fs::metadata(path).ok()?;
+        if !metadata.is_file() {
+            return Some(Stat {
+                modified: None,
+                len: 0,
+                hash: 0,
+            });
+        }
+        let modified = metadata.modified().ok();
+        let len = metadata.len();
+        if let Some(previous) = previous {
+            if previous.modified == modified && previous.len == len {
+                return Some(previous.clone());
+            }
+        }
+        let mut hasher = FnvHasher::default();
+        hasher.write(&fs::read(path).ok()?);
+        Some(Stat {
+            modified,
+            len,
+            hash: hasher.finish(),
+        })
+    }
+
+    // whether they'd make a difference to a build
+    fn same_contents(a: &Option<Stat>, b: &Option<Stat>) -> bool {
+        match (a, b) {
+            (Some(a), Some(b)) => a.len == b.len && a.hash == b.hash,
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Poller {
+    pub fn new(tx: mpsc::Sender<RawEvent>, interval: Duration) -> Self {
+        let files = Arc::new(Mutex::new(FnvHashMap::<PathBuf, Option<Stat>>::default()));
+        let quit = Arc::new(AtomicBool::new(false));
+        {
+            let files = Arc::clone(&files);
+            let quit = Arc::clone(&quit);
+            thread::spawn(move || loop {
+                thread::sleep(interval);
+                if quit.load(Ordering::SeqCst) {
+                    return;
+                }
+                // checked without the lock, since reading a file can take a while
+                let checked: Vec<_> = files
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(path, stat)| (path.clone(), stat.clone()))
+                    .collect();
+                for (path, stat) in checked {
+                    let new_stat = Stat::of(&path, stat.as_ref());
+                    if new_stat == stat {
+                        continue;
+                    }
+                    let changed = !Stat::same_contents(&stat, &new_stat);
+                    let op = match (&stat, &new_stat) {
+                        (None, _) => op::CREATE,
+                        (_, None) => op::REMOVE,
+                        _ => op::WRITE,
+                    };
+                    // unless it's been unwatched in the meantime
+                    if let Some(stat) = files.lock().unwrap().get_mut(&path) {
+                        *stat = new_stat;
+                    } else {
+                        continue;
+                    }
+                    if changed && !send(&tx, path, op) {
+                        return;
+                    }
+                }
+            });
+        }
+        Poller { files, quit }
+    }
+
+    pub fn watch(&self, path: &Path) {
+        let stat = Stat::of(path, None);
+        self.files.lock().unwrap().insert(path.to_owned(), stat);
+    }
+
+    pub fn unwatch(&self, path: &Path) {
+        self.files.lock().unwrap().remove(path);
+    }
+}
+
+impl Drop for Poller {
+    fn drop(&mut self) {
+        self.quit.store(true, Ordering::SeqCst);
+    }
+}
+
+// false once nothing's listening
+fn send(tx: &mpsc::Sender<RawEvent>, path: PathBuf, op: Op) -> bool {
+    tx.send(RawEvent {
+        path: Some(path),
+        op: Ok(op),
+        cookie: None,
+    })
+    .is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_poller() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.js");
+        let missing = dir.path().join("b.js");
+        fs::write(&file, "one").unwrap();
+        let (tx, rx) = mpsc::channel();
+        let poller = Poller::new(tx, Duration::from_millis(10));
+        poller.watch(&file);
+        poller.watch(&missing);
+        let next = || {
+            rx.recv_timeout(Duration::from_millis(500))
+                .ok()
+                .and_then(|event| Some((event.path?, event.op.ok()?)))
+        };
+        // so that it's never seen half-written
+        let write = |path: &Path, contents: &str| {
+            let temp = path.with_extension("tmp");
+            fs::write(&temp, contents).unwrap();
+            fs::rename(&temp, path).unwrap();
+        };
+
+        // rewriting a file with the same contents isn't a change
+        thread::sleep(Duration::from_millis(20));
+        write(&file, "one");
+        assert_eq!(next(), None);
+
+        write(&file, "two");
+        assert_eq!(next(), Some((file.clone(), op::WRITE)));
+        write(&missing, "");
+        assert_eq!(next(), Some((missing.clone(), op::CREATE)));
+        fs::remove_file(&file).unwrap();
+        assert_eq!(next(), Some((file.clone(), op::REMOVE)));
+
+        poller.unwatch(&missing);
+        fs::remove_file(&missing).unwrap();
+        assert_eq!(next(), None);
+    }
+}
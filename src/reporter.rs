@@ -0,0 +1,289 @@
+use crate::bundler::BuildStats;
+use crate::CliError;
+use esparse::ast::{Loc, SpanT};
+use serde::Serialize;
+use serde_json::json;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str;
+use std::time::Duration;
+
+// how watch mode reports what it's doing, on stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reporter {
+    // progress lines for people; `quiet` leaves out the bell on errors
+    Text { quiet: bool },
+    // an Event per line, for editors and test runners
+    Json,
+}
+
+impl str::FromStr for Reporter {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Reporter::Text { quiet: false }),
+            "json" => Ok(Reporter::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "camelCase")]
+enum Event<'a> {
+    // `scrumple serve` is listening
+    Serve {
+        url: &'a str,
+    },
+    // files changed, and the bundle will be rebuilt
+    Change {
+        paths: &'a [PathBuf],
+    },
+    #[serde(rename_all = "camelCase")]
+    BuildStart {
+        output: &'a str,
+        initial: bool,
+    },
+    // more files changed before it was done, so it starts over
+    BuildCancel,
+    #[serde(rename_all = "camelCase")]
+    BuildSuccess {
+        output: &'a str,
+        duration_ms: u64,
+        modules: usize,
+        // read and transformed, rather than reused from the last build
+        included: usize,
+        resolved: usize,
+        // None when it's written to stdout
+        output_bytes: Option<u64>,
+    },
+    #[serde(rename_all = "camelCase")]
+    BuildFailure {
+        output: &'a str,
+        duration_ms: u64,
+        error: serde_json::Value,
+    },
+    // --on-success or --on-error couldn't be started
+    HookFailure {
+        command: &'a str,
+        message: String,
+    },
+}
+
+impl Reporter {
+    pub fn serving(&self, url: &str) {
+        match *self {
+            Reporter::Text { .. } => eprintln!("serving {}", url),
+            Reporter::Json => emit(&Event::Serve { url }),
+        }
+    }
+
+    pub fn changed(&self, paths: &[PathBuf]) {
+        if *self == Reporter::Json && !paths.is_empty() {
+            emit(&Event::Change { paths });
+        }
+    }
+
+    pub fn build_started(&self, output: &str, initial: bool) {
+        match *self {
+            Reporter::Text { .. } => {
+                eprint!("{}", progress_line(output, initial));
+                let _ = io::stderr().flush();
+            }
+            Reporter::Json => emit(&Event::BuildStart { output, initial }),
+        }
+    }
+
+    // after it was cancelled; the text reporter is still on the same line
+    pub fn build_restarted(&self, output: &str) {
+        if *self == Reporter::Json {
+            emit(&Event::BuildStart {
+                output,
+                initial: false,
+            });
+        }
+    }
+
+    pub fn build_cancelled(&self) {
+        if *self == Reporter::Json {
+            emit(&Event::BuildCancel);
+        }
+    }
+
+    pub fn build_succeeded(
+        &self,
+        output: &str,
+        initial: bool,
+        elapsed: Duration,
+        stats: BuildStats,
+        output_bytes: Option<u64>,
+    ) {
+        let ms = millis(elapsed);
+        match *self {
+            Reporter::Text { .. } if initial => eprintln!(
+                "{bs} ready {output} in {ms} ms",
+                output = output,
+                ms = ms,
+                bs = "\u{8}".repeat(progress_line(output, initial).len())
+            ),
+            Reporter::Text { .. } => eprintln!(
+                "{bs}in {ms} ms ({stats})",
+                ms = ms,
+                stats = stats,
+                bs = "\u{8}".repeat(3)
+            ),
+            Reporter::Json => emit(&Event::BuildSuccess {
+                output,
+                duration_ms: ms,
+                modules: stats.modules,
+                included: stats.included,
+                resolved: stats.resolved,
+                output_bytes,
+            }),
+        }
+    }
+
+    // `exiting` if scrumple gives up after it, printing the error itself on the way out
+    pub fn build_failed(&self, output: &str, exiting: bool, elapsed: Duration, error: &CliError) {
+        match *self {
+            Reporter::Text { .. } if exiting => eprintln!(),
+            Reporter::Text { quiet } => {
+                eprintln!("{}error: {}", if quiet { "" } else { "\x07" }, error)
+            }
+            Reporter::Json => emit(&Event::BuildFailure {
+                output,
+                duration_ms: millis(elapsed),
+                error: error_json(error),
+            }),
+        }
+    }
+
+    pub fn hook_failed(&self, command: &str, error: &io::Error) {
+        match *self {
+            Reporter::Text { .. } => {
+                eprintln!("{}: couldn't run `{}`: {}", crate::EXE_NAME, command, error)
+            }
+            Reporter::Json => emit(&Event::HookFailure {
+                command,
+                message: error.to_string(),
+            }),
+        }
+    }
+}
+
+fn progress_line(output: &str, initial: bool) -> String {
+    if initial {
+        format!(" build {output} ...", output = output)
+    } else {
+        format!("update {} ...", output)
+    }
+}
+
+fn millis(elapsed: Duration) -> u64 {
+    elapsed.as_secs() * 1_000 + u64::from(elapsed.subsec_millis())
+}
+
+fn emit(event: &Event) {
+    let line = serde_json::to_string(event).expect("events are always serializable");
+    let stderr = io::stderr();
+    let mut handle = stderr.lock();
+    let _ = writeln!(handle, "{}", line);
+    let _ = handle.flush();
+}
+
+// the error's message, a `kind` to tell them apart, and whatever else is known about where it
+// happened
+fn error_json(error: &CliError) -> serde_json::Value {
+    let syntax = |span: &SpanT<String, Loc>| {
+        json!({
+            "kind": "syntax",
+            "file": span.file_name,
+            "line": span.start.row + 1,
+            "column": span.start.col + 1,
+        })
+    };
+    let mut value = match *error {
+        CliError::ModuleNotFound {
+            ref context,
+            ref name,
+            ref searched,
+            ref tried,
+            ref suggestions,
+        } => json!({
+            "kind": "moduleNotFound",
+            "file": context,
+            "name": name,
+            "searched": searched,
+            "tried": tried,
+            "suggestions": suggestions,
+        }),
+        CliError::CaseMismatch {
+            ref context,
+            ref path,
+            ref actual,
+        } => json!({
+            "kind": "caseMismatch",
+            "file": context,
+            "path": path,
+            "actual": actual,
+        }),
+        CliError::InvalidUtf8 { ref context, .. } => {
+            json!({ "kind": "invalidUtf8", "file": context })
+        }
+        CliError::Lex(ref inner) => syntax(&inner.span),
+        CliError::Es6(ref inner) => syntax(&inner.span),
+        CliError::Io(_) => json!({ "kind": "io" }),
+        CliError::WorkerPanic { .. } => json!({ "kind": "internal" }),
+        _ => json!({ "kind": "other" }),
+    };
+    value["message"] = json!(error.to_string());
+    value
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_error_json() {
+        let error = CliError::ModuleNotFound {
+            context: Path::new("/a/index.js").to_owned(),
+            name: "./b".to_owned(),
+            searched: vec![],
+            tried: vec![Path::new("/a/b.js").to_owned()],
+            suggestions: vec!["./c".to_owned()],
+        };
+        let value = error_json(&error);
+        assert_eq!(value["kind"], "moduleNotFound");
+        assert_eq!(value["file"], "/a/index.js");
+        assert_eq!(value["name"], "./b");
+        assert_eq!(value["tried"], json!(["/a/b.js"]));
+        assert_eq!(value["message"], json!(error.to_string()));
+
+        let value = error_json(&CliError::BadUsage("nope"));
+        assert_eq!(value["kind"], "other");
+        assert_eq!(
+            value["message"],
+            json!(CliError::BadUsage("nope").to_string())
+        );
+    }
+
+    #[test]
+    fn test_event_json() {
+        let paths = [Path::new("/a/b.js").to_owned()];
+        let event = serde_json::to_value(&Event::Change { paths: &paths }).unwrap();
+        assert_eq!(event, json!({ "event": "change", "paths": ["/a/b.js"] }));
+        let event = serde_json::to_value(&Event::BuildFailure {
+            output: "-",
+            duration_ms: 3,
+            error: json!({}),
+        })
+        .unwrap();
+        assert_eq!(
+            event,
+            json!({ "event": "buildFailure", "output": "-", "durationMs": 3, "error": {} })
+        );
+    }
+}
@@ -1,19 +1,34 @@
+mod suggest;
 #[cfg(test)]
 mod test;
+mod trace;
+
+pub use self::trace::{Outcome, TraceStep};
 
 use crate::input_options::{InputOptions, PackageManager};
-use crate::manifest::{BrowserSubstitution, PackageCache, PackageInfo};
+use crate::manifest::{
+    BrowserSubstitution, PackageCache, PackageInfo, PackageType, SubpathMap, EXPORTS_CONDITIONS,
+};
 use crate::path_ext::*;
+use crate::pnp::{self, PnpResolution};
 use crate::CliError;
 use matches::matches;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::ffi::OsStr;
+use std::fs;
+use std::mem;
 use std::path::{self, Path, PathBuf};
+use std::sync::Arc;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum ModuleSubstitution {
     Normal,
     Ignore,
     External,
-    Replace(String),
+    // the new name, and the directory of the package.json that substitutes it
+    Replace(String, PathBuf),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -24,7 +39,7 @@ enum PathSubstitution {
     Replace(PathBuf),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Resolved {
     External,
     Ignore,
@@ -35,17 +50,148 @@ pub enum Resolved {
 #[derive(Debug, Clone, Default)]
 pub struct Resolver {
     input_options: InputOptions,
-    pub cache: PackageCache,
+    pub cache: Arc<PackageCache>,
+    // only recorded once enabled by with_trace()
+    trace: RefCell<Option<Vec<TraceStep>>>,
 }
 
 impl Resolver {
     pub fn new(input_options: InputOptions) -> Self {
+        let cache = Arc::new(PackageCache::new(input_options.main_fields.clone()));
+        Self::with_cache(input_options, cache)
+    }
+
+    // `cache` must have been created with the same main fields
+    pub fn with_cache(input_options: InputOptions, cache: Arc<PackageCache>) -> Self {
         Resolver {
+            cache,
             input_options,
-            ..Default::default()
+            trace: RefCell::new(None),
+        }
+    }
+
+    pub fn with_trace(self) -> Self {
+        *self.trace.borrow_mut() = Some(Vec::new());
+        self
+    }
+
+    // the steps recorded since the last call
+    pub fn take_trace(&self) -> Vec<TraceStep> {
+        self.trace
+            .borrow_mut()
+            .as_mut()
+            .map(mem::take)
+            .unwrap_or_default()
+    }
+
+    fn trace<F: FnOnce() -> TraceStep>(&self, step: F) {
+        if let Some(ref mut trace) = *self.trace.borrow_mut() {
+            trace.push(step());
+        }
+    }
+
+    fn trace_manifest(&self, dir: &Path, info: &PackageInfo, package_manager: PackageManager) {
+        let path = manifest_path(dir, package_manager);
+        if let Some(ref mut trace) = *self.trace.borrow_mut() {
+            // consulted over and over while probing
+            if !trace
+                .iter()
+                .any(|step| matches!(step, TraceStep::Manifest { path: p, .. } if *p == path))
+            {
+                trace.push(TraceStep::Manifest {
+                    path,
+                    main: info.main.clone(),
+                    main_field: info.main_field,
+                });
+            }
+        }
+    }
+
+    // the manifests `dir` could have had ahead of the one it has, or all of them if `found` is
+    // false
+    fn trace_no_manifest(&self, dir: &Path, found: bool, package_manager: PackageManager) {
+        if let Some(ref mut trace) = *self.trace.borrow_mut() {
+            let used = if found {
+                Some(manifest_path(dir, package_manager))
+            } else {
+                None
+            };
+            for file in package_manager.files() {
+                let path = dir.join(file);
+                if Some(&path) == used.as_ref() {
+                    break;
+                }
+                if !trace
+                    .iter()
+                    .any(|step| matches!(step, TraceStep::NoManifest { path: p } if *p == path))
+                {
+                    trace.push(TraceStep::NoManifest { path });
+                }
+            }
         }
     }
 
+    fn package_info(
+        &self,
+        dir: &Path,
+        package_manager: PackageManager,
+    ) -> Result<Option<Arc<PackageInfo>>, CliError> {
+        let info = self.cache.package_info(dir, package_manager)?;
+        self.trace_no_manifest(dir, info.is_some(), package_manager);
+        if let Some(ref info) = info {
+            self.trace_manifest(dir, info, package_manager);
+        }
+        Ok(info)
+    }
+
+    fn nearest_package(
+        &self,
+        dir: PathBuf,
+        package_manager: PackageManager,
+    ) -> Result<Option<(PathBuf, Arc<PackageInfo>)>, CliError> {
+        let found = self.cache.nearest_package(dir.clone(), package_manager)?;
+        let found_dir = found.as_ref().map(|(dir, _)| dir.as_path());
+        // the directories it looked in on the way up, as PackageCache::nearest_package does
+        for ancestor in dir.ancestors() {
+            if Some(ancestor) == found_dir {
+                break;
+            }
+            if ancestor.file_name() != Some(OsStr::new(package_manager.dir())) {
+                self.trace_no_manifest(ancestor, false, package_manager);
+            }
+        }
+        if let Some((ref dir, ref info)) = found {
+            self.trace_no_manifest(dir, true, package_manager);
+            self.trace_manifest(dir, info, package_manager);
+        }
+        Ok(found)
+    }
+
+    // finds the directory of the package `name` in the workspaces of the nearest workspace root
+    // above `dir`; this works whether or not they've been linked into node_modules
+    fn workspace_package(
+        &self,
+        dir: &Path,
+        name: &str,
+        package_manager: PackageManager,
+    ) -> Result<Option<PathBuf>, CliError> {
+        for dir in dir.ancestors() {
+            if let Some(info) = self.package_info(dir, package_manager)? {
+                if !info.workspaces.is_empty() {
+                    let workspace = self
+                        .cache
+                        .workspace(dir, &info.workspaces, package_manager)?;
+                    // the manifests it took the names from, which the cache read once
+                    for package_dir in &workspace.dirs {
+                        self.package_info(package_dir, package_manager)?;
+                    }
+                    return Ok(workspace.packages.get(name).cloned());
+                }
+            }
+        }
+        Ok(None)
+    }
+
     #[inline]
     fn needs_dir(name: &str, path: &Path) -> bool {
         name.ends_with('/')
@@ -66,29 +212,83 @@ impl Resolver {
             false,
             self.input_options.package_manager,
         )?
+        .map(|resolved| self.real_resolved(resolved))
         .ok_or_else(|| CliError::MainNotFound {
             name: name.to_owned(),
         })
     }
 
     pub fn resolve(&self, context: &Path, name: &str) -> Result<Resolved, CliError> {
+        match self.resolve_substituted(context, name, &mut Vec::new()) {
+            Ok(resolved) => Ok(self.real_resolved(resolved)),
+            Err(CliError::ModuleNotFound {
+                context: error_context,
+                name: error_name,
+                ..
+            }) => Err(self.explain_not_found(context, name, error_context, error_name)),
+            Err(error) => Err(error),
+        }
+    }
+
+    // resolves `name` again to collect the paths it tried, then looks around for what was meant
+    fn explain_not_found(
+        &self,
+        context: &Path,
+        name: &str,
+        error_context: PathBuf,
+        error_name: String,
+    ) -> CliError {
+        let saved = self.trace.replace(Some(Vec::new()));
+        let _ = self.resolve_substituted(context, name, &mut Vec::new());
+        let steps = self.trace.replace(saved).unwrap_or_default();
+        let mut tried = Vec::new();
+        for step in steps {
+            if let TraceStep::Candidate {
+                path,
+                outcome: Outcome::Missing,
+            } = step
+            {
+                if !tried.contains(&path) {
+                    tried.push(path);
+                }
+            }
+        }
+        let (searched, suggestions) = self.suggest(&error_context, &error_name);
+        CliError::ModuleNotFound {
+            context: error_context,
+            name: error_name,
+            searched,
+            tried,
+            suggestions,
+        }
+    }
+
+    fn real_resolved(&self, resolved: Resolved) -> Resolved {
+        match resolved {
+            Resolved::Normal(path) => {
+                Resolved::Normal(real_path(path, self.input_options.preserve_symlinks))
+            }
+            resolved => resolved,
+        }
+    }
+
+    // `substituted` holds the names that browser substitutions have already replaced, with the
+    // manifest that replaced each one
+    fn resolve_substituted(
+        &self,
+        context: &Path,
+        name: &str,
+        substituted: &mut Vec<(PathBuf, String)>,
+    ) -> Result<Resolved, CliError> {
         if name.is_empty() {
             return Err(CliError::EmptyModuleName {
                 context: context.to_owned(),
             });
         }
 
-        let package_name = if name.contains("/") {
-            name.split("/").next().unwrap()
-        } else {
-            name
-        };
-
-        let package_manager = if self.input_options.forced_npm_deps.contains(package_name) {
-            PackageManager::Npm
-        } else {
-            self.input_options.package_manager
-        };
+        let name = &*self.apply_aliases(context, name)?;
+        let (package_name, subpath) = split_package_name(name);
+        let package_manager = self.package_manager_for(package_name);
 
         let path = Path::new(name);
         let needs_dir = Self::needs_dir(name, path);
@@ -101,67 +301,281 @@ impl Resolver {
                     false,
                     package_manager,
                 )?
-                .ok_or_else(|| CliError::ModuleNotFound {
-                    context: context.to_owned(),
-                    name: name.to_owned(),
-                })?)
+                .ok_or_else(|| CliError::module_not_found(context, name))?)
         } else if path.is_explicitly_relative() {
             let mut dir = context.to_owned();
             let did_pop = dir.pop(); // to directory
             debug_assert!(did_pop);
             dir.append_resolving(path);
-            let package_manager = if self.input_options.forced_npm_deps.contains(package_name) {
-                PackageManager::Npm
-            } else {
-                self.input_options.package_manager
-            };
             Ok(self
                 .resolve_path_or_module(Some(context), dir, needs_dir, false, package_manager)?
-                .ok_or_else(|| CliError::ModuleNotFound {
-                    context: context.to_owned(),
-                    name: name.to_owned(),
-                })?)
+                .ok_or_else(|| CliError::module_not_found(context, name))?)
+        } else if name.starts_with('#') {
+            self.resolve_imports(context, name, package_manager)
         } else {
             match self.module_substitution(context, name)? {
                 ModuleSubstitution::Ignore => return Ok(Resolved::Ignore),
                 ModuleSubstitution::External => return Ok(Resolved::External),
-                ModuleSubstitution::Replace(new_name) => {
-                    // TODO: detect cycles
+                ModuleSubstitution::Replace(new_name, package_dir) => {
                     // eprintln!("module replace {} => {}", name, &new_name);
-                    return self.resolve(context, &new_name);
+                    // the same substitution again; another manifest can map the same name on
+                    let manifest = manifest_path(&package_dir, package_manager);
+                    let is_cycle = substituted.iter().any(|(prev_manifest, prev)| {
+                        *prev_manifest == manifest && split_package_name(prev).0 == package_name
+                    });
+                    if is_cycle {
+                        let mut chain: Vec<_> =
+                            substituted.drain(..).map(|(_, name)| name).collect();
+                        chain.push(name.to_owned());
+                        return Err(CliError::SubstitutionCycle {
+                            context: context.to_owned(),
+                            manifest,
+                            chain,
+                        });
+                    }
+                    substituted.push((manifest, name.to_owned()));
+                    return self.resolve_substituted(context, &new_name, substituted);
                 }
                 ModuleSubstitution::Normal => {}
             }
 
-            let component_dir = package_manager.dir();
-
-            let mut suffix = PathBuf::from(component_dir);
-            for part in path.components() {
-                suffix.push(part);
+            if package_manager == PackageManager::Pnp {
+                if let Some(package_dir) = self.resolve_pnp(context, package_name)? {
+                    return self
+                        .resolve_in_package(
+                            context,
+                            package_dir,
+                            package_name,
+                            subpath,
+                            needs_dir,
+                            package_manager,
+                        )?
+                        .ok_or_else(|| CliError::module_not_found(context, name));
+                }
+                // not part of a PnP project, so look in node_modules
             }
 
+            let component_dir = package_manager.dir();
+
             let mut dir = context.to_owned();
             while dir.pop() {
                 match dir.file_name() {
                     Some(s) if s == component_dir => continue,
                     _ => {}
                 }
-                let new_path = dir.join(&suffix);
-                if let Some(result) = self.resolve_path_or_module(
-                    Some(context),
-                    new_path,
+
+                let mut package_dir = dir.join(component_dir);
+                package_dir.push(package_name);
+                if let Some(result) = self.resolve_in_package(
+                    context,
+                    package_dir,
+                    package_name,
+                    subpath,
                     needs_dir,
-                    false,
                     package_manager,
                 )? {
                     return Ok(result);
                 }
             }
 
-            Err(CliError::ModuleNotFound {
+            // workspace packages that haven't been linked into node_modules
+            if let Some(package_dir) =
+                self.workspace_package(context.parent().unwrap(), package_name, package_manager)?
+            {
+                if let Some(result) = self.resolve_in_package(
+                    context,
+                    package_dir,
+                    package_name,
+                    subpath,
+                    needs_dir,
+                    package_manager,
+                )? {
+                    return Ok(result);
+                }
+            }
+
+            Err(CliError::module_not_found(context, name))
+        }
+    }
+
+    // `name` rewritten by the most specific alias that matches it, and again by the next, until
+    // none does. each alias applies at most once, so "pkg" -> "pkg/dist/pkg.js" terminates; coming
+    // back to an earlier name is a cycle
+    fn apply_aliases<'a>(&self, context: &Path, name: &'a str) -> Result<Cow<'a, str>, CliError> {
+        let aliases = &self.input_options.aliases.0;
+        let mut used = vec![false; aliases.len()];
+        let mut chain = vec![name.to_owned()];
+        loop {
+            let current = chain.last().unwrap();
+            let next = aliases
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| !used[i])
+                .find_map(|(i, alias)| alias.apply(current).map(|next| (i, next)));
+            match next {
+                Some((i, next)) => {
+                    used[i] = true;
+                    self.trace(|| TraceStep::Alias {
+                        from: current.clone(),
+                        to: next.clone(),
+                    });
+                    let is_cycle = chain.contains(&next);
+                    chain.push(next);
+                    if is_cycle {
+                        return Err(CliError::AliasCycle {
+                            context: context.to_owned(),
+                            chain,
+                        });
+                    }
+                }
+                None if chain.len() == 1 => return Ok(Cow::Borrowed(name)),
+                None => return Ok(Cow::Owned(chain.pop().unwrap())),
+            }
+        }
+    }
+
+    // the "type" of the package that contains `module`
+    pub fn package_type(&self, module: &Path) -> Result<PackageType, CliError> {
+        let dir = match module.parent() {
+            Some(dir) => dir.to_owned(),
+            None => return Ok(PackageType::default()),
+        };
+        Ok(self
+            .cache
+            .nearest_package_info(dir, self.input_options.package_manager)?
+            .map_or_else(PackageType::default, |info| info.package_type))
+    }
+
+    fn resolve_pnp(&self, context: &Path, package_name: &str) -> Result<Option<PathBuf>, CliError> {
+        let data = match self.cache.pnp_data(context.parent().unwrap().to_owned())? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        match data.resolve(context, package_name) {
+            PnpResolution::Outside => Ok(None),
+            PnpResolution::Package(location) => match pnp::zip_archive(&location) {
+                Some(archive) => Err(CliError::PnpZipArchive {
+                    context: context.to_owned(),
+                    name: package_name.to_owned(),
+                    archive,
+                }),
+                None => Ok(Some(location)),
+            },
+            PnpResolution::Undeclared { issuer } => Err(CliError::PnpUndeclaredDependency {
+                context: context.to_owned(),
+                issuer,
+                name: package_name.to_owned(),
+            }),
+            PnpResolution::MissingPeer { issuer } => Err(CliError::PnpMissingPeerDependency {
+                context: context.to_owned(),
+                issuer,
+                name: package_name.to_owned(),
+            }),
+        }
+    }
+
+    // resolves `subpath` of the package at `package_dir`, which may not exist
+    fn resolve_in_package(
+        &self,
+        context: &Path,
+        mut package_dir: PathBuf,
+        package_name: &str,
+        subpath: &str,
+        needs_dir: bool,
+        package_manager: PackageManager,
+    ) -> Result<Option<Resolved>, CliError> {
+        if let Some(info) = self.package_info(&package_dir, package_manager)? {
+            if let Some(ref exports) = info.exports {
+                return self
+                    .resolve_exports(context, package_dir, &info, exports, package_name, subpath)
+                    .map(Some);
+            }
+        }
+
+        for part in Path::new(subpath.trim_start_matches('/')).components() {
+            package_dir.push(part);
+        }
+        self.resolve_path_or_module(
+            Some(context),
+            package_dir,
+            needs_dir,
+            false,
+            package_manager,
+        )
+    }
+
+    fn resolve_exports(
+        &self,
+        context: &Path,
+        mut package_dir: PathBuf,
+        info: &PackageInfo,
+        exports: &SubpathMap,
+        package_name: &str,
+        subpath: &str,
+    ) -> Result<Resolved, CliError> {
+        let subpath = format!(".{}", subpath);
+        let target = exports
+            .resolve_export(&subpath, EXPORTS_CONDITIONS)
+            .ok_or_else(|| CliError::SubpathNotExported {
                 context: context.to_owned(),
-                name: name.to_owned(),
-            })
+                package: package_name.to_owned(),
+                subpath: subpath.clone(),
+            })?;
+        package_dir.append_resolving(&target);
+        self.resolve_target(context, info, package_dir, || {
+            format!("{}{}", package_name, &subpath[1..])
+        })
+    }
+
+    fn resolve_imports(
+        &self,
+        context: &Path,
+        name: &str,
+        package_manager: PackageManager,
+    ) -> Result<Resolved, CliError> {
+        let not_defined = || CliError::ImportNotDefined {
+            context: context.to_owned(),
+            name: name.to_owned(),
+        };
+        let dir = context.parent().ok_or_else(not_defined)?.to_owned();
+        let (mut package_dir, info) = self
+            .nearest_package(dir, package_manager)?
+            .ok_or_else(not_defined)?;
+        let target = info
+            .imports
+            .as_ref()
+            .and_then(|imports| imports.resolve_import(name, EXPORTS_CONDITIONS))
+            .ok_or_else(not_defined)?;
+
+        if !target.starts_with('.') {
+            // a bare target names a dependency of this package
+            return self.resolve_substituted(context, &target, &mut Vec::new());
+        }
+        package_dir.append_resolving(&target);
+        self.resolve_target(context, &info, package_dir, || name.to_owned())
+    }
+
+    // exports and imports targets are exact file paths; no extension or index probing
+    fn resolve_target<F: FnOnce() -> String>(
+        &self,
+        context: &Path,
+        info: &PackageInfo,
+        path: PathBuf,
+        name: F,
+    ) -> Result<Resolved, CliError> {
+        match self.check_path(Some(context), Some(info), &path, false)? {
+            PathSubstitution::Normal => Ok(Resolved::Normal(path)),
+            PathSubstitution::Ignore => Ok(Resolved::Ignore),
+            PathSubstitution::Replace(path) => Ok(Resolved::Normal(path)),
+            PathSubstitution::Missing => Err(CliError::module_not_found(context, &name())),
+        }
+    }
+
+    fn package_manager_for(&self, package_name: &str) -> PackageManager {
+        if self.input_options.forced_npm_deps.contains(package_name) {
+            PackageManager::Npm
+        } else {
+            self.input_options.package_manager
         }
     }
 
@@ -170,26 +584,33 @@ impl Resolver {
         context: &Path,
         name: &str,
     ) -> Result<ModuleSubstitution, CliError> {
-        let module_name = name.split('/').next().unwrap();
+        let (module_name, _) = split_package_name(name);
         if self.input_options.external.contains(module_name) {
             return Ok(ModuleSubstitution::External);
         }
         if let Some(p) = context.parent() {
-            let package_manager = if self.input_options.forced_npm_deps.contains(module_name) {
-                PackageManager::Npm
-            } else {
-                self.input_options.package_manager
-            };
-            if let Some(info) = self
-                .cache
-                .nearest_package_info(p.to_owned(), package_manager)?
+            let package_manager = self.package_manager_for(module_name);
+            if let Some((package_dir, info)) =
+                self.nearest_package(p.to_owned(), package_manager)?
             {
                 match info.browser_substitutions.0.get(Path::new(module_name)) {
-                    Some(&BrowserSubstitution::Ignore) => return Ok(ModuleSubstitution::Ignore),
+                    Some(&BrowserSubstitution::Ignore) => {
+                        self.trace(|| TraceStep::Substitution {
+                            from: name.to_owned(),
+                            to: None,
+                            manifest: manifest_path(&package_dir, package_manager),
+                        });
+                        return Ok(ModuleSubstitution::Ignore);
+                    }
                     Some(&BrowserSubstitution::Replace(ref to)) => {
                         let mut new_name = to.to_string_lossy().into_owned();
                         new_name.push_str(&name[module_name.len()..]);
-                        return Ok(ModuleSubstitution::Replace(new_name));
+                        self.trace(|| TraceStep::Substitution {
+                            from: name.to_owned(),
+                            to: Some(new_name.clone()),
+                            manifest: manifest_path(&package_dir, package_manager),
+                        });
+                        return Ok(ModuleSubstitution::Replace(new_name, package_dir));
                     }
                     None => {}
                 }
@@ -207,17 +628,18 @@ impl Resolver {
         package_manager: PackageManager,
     ) -> Result<Option<Resolved>, CliError> {
         let package_info = self
-            .cache
-            .nearest_package_info(path.clone(), package_manager)?;
+            .nearest_package(path.clone(), package_manager)?
+            .map(|(_, info)| info);
 
         macro_rules! check_path {
             ( $package_info:ident, $path:ident ) => {
                 // eprintln!("check {}", $path.display());
-                match Self::check_path(
+                match self.check_path(
+                    context,
                     $package_info.as_ref().map(|x| x.as_ref()),
                     &$path,
                     is_package,
-                ) {
+                )? {
                     PathSubstitution::Normal => {
                         // eprintln!("resolve {}", $path.display());
                         return Ok(Some(Resolved::Normal($path)));
@@ -246,52 +668,103 @@ impl Resolver {
 
             let mut new_file_name = file_name.to_owned();
 
-            // <path>.mjs
-            new_file_name.push(".mjs");
-            path.set_file_name(&new_file_name);
-            check_path!(package_info, path);
-            new_file_name.clear();
-            new_file_name.push(&file_name);
-
-            // <path>.js
-            new_file_name.push(".js");
-            path.set_file_name(&new_file_name);
-            check_path!(package_info, path);
-
-            // <path>.json
-            new_file_name.push("on"); // .js|on
-            path.set_file_name(&new_file_name);
-            check_path!(package_info, path);
+            // <path><ext>, .mjs, .js, .cjs and .json by default
+            for ext in &self.input_options.extensions.0 {
+                new_file_name.clear();
+                new_file_name.push(&file_name);
+                new_file_name.push(ext);
+                path.set_file_name(&new_file_name);
+                check_path!(package_info, path);
+            }
 
             path.set_file_name(&file_name);
         }
 
         if !is_package {
-            if let Some(info) = self.cache.package_info(&mut path, package_manager)? {
+            if let Some(info) = self.package_info(&path, package_manager)? {
                 path.replace_with(&info.main);
                 return self.resolve_path_or_module(context, path, false, true, package_manager);
             }
         }
 
-        // <path>/index.mjs
-        path.push("index.mjs");
-        check_path!(package_info, path);
-        path.pop();
-
-        // <path>/index.js
-        path.push("index.js");
-        check_path!(package_info, path);
-        path.pop();
-
-        // <path>/index.json
-        path.push("index.json");
-        check_path!(package_info, path);
-        // NO path.pop();
+        // <path>/<index>, index.mjs, index.js and index.json by default
+        for index_file in &self.input_options.index_files.0 {
+            path.push(index_file);
+            check_path!(package_info, path);
+            path.pop();
+        }
 
         Ok(None)
     }
 
     fn check_path(
+        &self,
+        context: Option<&Path>,
+        package_info: Option<&PackageInfo>,
+        path: &Path,
+        is_package: bool,
+    ) -> Result<PathSubstitution, CliError> {
+        let substitution = Self::path_substitution(package_info, path, is_package);
+        self.trace(|| TraceStep::Candidate {
+            path: path.to_owned(),
+            outcome: match substitution {
+                PathSubstitution::Missing => Outcome::Missing,
+                PathSubstitution::Normal => Outcome::Found,
+                PathSubstitution::Ignore => Outcome::Ignored,
+                PathSubstitution::Replace(ref to) => Outcome::Replaced { to: to.clone() },
+            },
+        });
+        if substitution == PathSubstitution::Normal && self.input_options.strict_case {
+            if let Some(actual) = self.case_mismatch(path) {
+                return Err(CliError::CaseMismatch {
+                    context: context.map(Path::to_owned),
+                    path: path.to_owned(),
+                    actual,
+                });
+            }
+        }
+        Ok(substitution)
+    }
+
+    // `path` as the directory listings spell it, if that differs from `path` in case; a
+    // case-insensitive filesystem finds the file either way, but other systems won't
+    fn case_mismatch(&self, path: &Path) -> Option<PathBuf> {
+        let mut actual = PathBuf::new();
+        let mut mismatch = false;
+        for component in path.components() {
+            let name = match component {
+                path::Component::Normal(name) => name,
+                _ => {
+                    actual.push(component);
+                    continue;
+                }
+            };
+            let spelled = self.cache.dir_entries(&actual).and_then(|entries| {
+                if entries.iter().any(|entry| entry == name) {
+                    return None;
+                }
+                let lower = name.to_string_lossy().to_lowercase();
+                entries
+                    .iter()
+                    .find(|entry| entry.to_string_lossy().to_lowercase() == lower)
+                    .cloned()
+            });
+            match spelled {
+                Some(spelled) => {
+                    mismatch = true;
+                    actual.push(spelled);
+                }
+                None => actual.push(name),
+            }
+        }
+        if mismatch {
+            Some(actual)
+        } else {
+            None
+        }
+    }
+
+    fn path_substitution(
         package_info: Option<&PackageInfo>,
         path: &Path,
         is_package: bool,
@@ -319,3 +792,36 @@ impl Resolver {
         }
     }
 }
+
+// the manifest file in the package directory `dir`
+fn manifest_path(dir: &Path, package_manager: PackageManager) -> PathBuf {
+    let files = package_manager.files();
+    files
+        .iter()
+        .map(|file| dir.join(file))
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| dir.join(files[0]))
+}
+
+// resolves symlinks, so a file reached through several links is bundled once and requires from
+// it are looked up from where it really is
+pub fn real_path(path: PathBuf, preserve_symlinks: bool) -> PathBuf {
+    if preserve_symlinks {
+        path
+    } else {
+        // leave paths that don't exist for reading them to report
+        fs::canonicalize(&path).unwrap_or(path)
+    }
+}
+
+// "pkg/sub/path" => ("pkg", "/sub/path"), "@scope/pkg" => ("@scope/pkg", "")
+pub fn split_package_name(name: &str) -> (&str, &str) {
+    let mut slashes = name.match_indices('/').map(|(i, _)| i);
+    if name.starts_with('@') {
+        slashes.next();
+    }
+    match slashes.next() {
+        Some(i) => name.split_at(i),
+        None => (name, ""),
+    }
+}
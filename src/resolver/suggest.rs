@@ -0,0 +1,247 @@
+use super::{split_package_name, Resolver};
+use crate::input_options::PackageManager;
+use crate::path_ext::*;
+use std::path::{Path, PathBuf};
+use std::{cmp, fmt, fs};
+
+const MAX_SUGGESTIONS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Likeness {
+    Case,
+    Extension,
+    Spelling(usize),
+}
+
+impl fmt::Display for Likeness {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Likeness::Case => "differs in case",
+            Likeness::Extension => "different extension",
+            Likeness::Spelling(_) => "similar name",
+        })
+    }
+}
+
+impl Resolver {
+    // the directories a failed require of `name` looked in, and what it might have meant
+    pub(super) fn suggest(&self, context: &Path, name: &str) -> (Vec<PathBuf>, Vec<String>) {
+        let path = Path::new(name);
+        if path.is_absolute() || path.is_explicitly_relative() {
+            let mut target = context.parent().unwrap().to_owned();
+            target.append_resolving(path);
+            let dir = if name.ends_with('/') {
+                target.clone()
+            } else {
+                target.parent().unwrap_or(&target).to_owned()
+            };
+            let suggestions = match path.file_name() {
+                Some(file_name) if !name.ends_with('/') => {
+                    self.similar_entries(&dir, &file_name.to_string_lossy(), parent_prefix(name))
+                }
+                _ => Vec::new(),
+            };
+            return (vec![dir], suggestions);
+        }
+        if name.starts_with('#') {
+            return (Vec::new(), Vec::new());
+        }
+
+        let (package_name, subpath) = split_package_name(name);
+        let package_manager = self.package_manager_for(package_name);
+        let component_dir = package_manager.dir();
+        let other_manager = match package_manager {
+            PackageManager::Bower => PackageManager::Npm,
+            PackageManager::Npm | PackageManager::Pnp => PackageManager::Bower,
+        };
+
+        let mut searched = Vec::new();
+        let mut package_dir = None;
+        let mut other_package_dir = None;
+        let mut dir = context.to_owned();
+        while dir.pop() {
+            if matches!(dir.file_name(), Some(s) if s == component_dir) {
+                continue;
+            }
+            let modules = dir.join(component_dir);
+            if modules.is_dir() {
+                if package_dir.is_none() && modules.join(package_name).is_dir() {
+                    package_dir = Some(modules.join(package_name));
+                }
+                searched.push(modules);
+            }
+            let other = dir.join(other_manager.dir()).join(package_name);
+            if other_package_dir.is_none() && other.is_dir() {
+                other_package_dir = Some(other);
+            }
+        }
+
+        let mut suggestions = Vec::new();
+        match package_dir {
+            // the package is there, but not the file
+            Some(mut package_dir) => {
+                let subpath = subpath.trim_start_matches('/');
+                if !subpath.is_empty() && !name.ends_with('/') {
+                    package_dir.append_resolving(subpath);
+                    let file_name = package_dir
+                        .file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned();
+                    package_dir.pop();
+                    suggestions =
+                        self.similar_entries(&package_dir, &file_name, parent_prefix(name));
+                }
+            }
+            None => {
+                // scoped packages are listed in node_modules/@scope
+                let (scope, unscoped) = match package_name.find('/') {
+                    Some(i) => (&package_name[..=i], &package_name[i + 1..]),
+                    None => ("", package_name),
+                };
+                let mut likenesses: Vec<(Likeness, String)> = Vec::new();
+                for modules in &searched {
+                    let dir = modules.join(scope);
+                    for (likeness, entry) in similar_names(&dir, unscoped, &[]) {
+                        let suggestion = format!("{}{}", scope, entry);
+                        if !likenesses.iter().any(|(_, s)| *s == suggestion) {
+                            likenesses.push((likeness, suggestion));
+                        }
+                    }
+                }
+                likenesses.sort();
+                suggestions = likenesses
+                    .into_iter()
+                    .map(|(likeness, suggestion)| format!("'{}' ({})", suggestion, likeness))
+                    .collect();
+            }
+        }
+
+        if let Some(other) = other_package_dir {
+            let hint = match other_manager {
+                PackageManager::Bower => "use --for-bower to resolve packages from there",
+                PackageManager::Npm | PackageManager::Pnp => {
+                    "use --allow-npm-dev-deps or drop --for-bower to resolve it from there"
+                }
+            };
+            suggestions.insert(
+                0,
+                format!("'{}' is in {}; {}", package_name, other.display(), hint),
+            );
+        }
+        suggestions.truncate(MAX_SUGGESTIONS);
+        (searched, suggestions)
+    }
+
+    fn similar_entries(&self, dir: &Path, requested: &str, prefix: &str) -> Vec<String> {
+        similar_names(dir, requested, &self.input_options.extensions.0)
+            .into_iter()
+            .map(|(likeness, entry)| format!("'{}{}' ({})", prefix, entry, likeness))
+            .collect()
+    }
+}
+
+// "./a/b" => "./a/", "pkg/lib/x" => "pkg/lib/"
+fn parent_prefix(name: &str) -> &str {
+    match name.rfind('/') {
+        Some(i) => &name[..=i],
+        None => "",
+    }
+}
+
+fn stem(name: &str) -> &str {
+    match name.rfind('.') {
+        Some(i) if i > 0 => &name[..i],
+        _ => name,
+    }
+}
+
+// entries of `dir` that look like `requested`, best first; entries with one of `extensions` are
+// named without it when `requested` has none, since that's how they'd be required
+fn similar_names(dir: &Path, requested: &str, extensions: &[String]) -> Vec<(Likeness, String)> {
+    let requested_stem = stem(requested);
+    let requested_lower = requested.to_lowercase();
+    let stem_lower = requested_stem.to_lowercase();
+    let max_distance = cmp::max(1, stem_lower.chars().count() / 3);
+
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+    {
+        let entry = entry.file_name().to_string_lossy().into_owned();
+        if entry.starts_with('.') || entry == "node_modules" || entry == requested {
+            continue;
+        }
+        let entry_stem = stem(&entry);
+        let entry_stem_lower = entry_stem.to_lowercase();
+        let likeness = if entry.to_lowercase() == requested_lower
+            || (entry_stem != requested && entry_stem_lower == requested_lower)
+            || (entry_stem != requested_stem && entry_stem_lower == stem_lower)
+        {
+            Likeness::Case
+        } else if entry_stem == requested_stem || entry_stem == requested {
+            Likeness::Extension
+        } else {
+            match distance(&entry_stem_lower, &stem_lower) {
+                d if d <= max_distance => Likeness::Spelling(d),
+                _ => continue,
+            }
+        };
+
+        let has_known_extension = extensions
+            .iter()
+            .any(|ext| !ext.is_empty() && entry.ends_with(ext.as_str()));
+        let name = if requested_stem == requested && has_known_extension {
+            entry_stem.to_owned()
+        } else {
+            entry.clone()
+        };
+        entries.push((likeness, name));
+    }
+    entries.sort();
+    entries.dedup_by(|a, b| a.1 == b.1);
+    entries
+}
+
+// optimal string alignment distance: edits, counting a swap of neighbouring characters as one
+fn distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            d[i][j] = cmp::min(
+                cmp::min(d[i - 1][j] + 1, d[i][j - 1] + 1),
+                d[i - 1][j - 1] + cost,
+            );
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = cmp::min(d[i][j], d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_distance() {
+        assert_eq!(distance("lodash", "lodash"), 0);
+        assert_eq!(distance("lodash", "lodahs"), 1);
+        assert_eq!(distance("formatter", "formater"), 1);
+        assert_eq!(distance("react", "preact"), 1);
+        assert_eq!(distance("abc", ""), 3);
+        assert_eq!(distance("kitten", "sitting"), 3);
+    }
+}
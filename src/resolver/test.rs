@@ -1,10 +1,13 @@
 use super::*;
-use crate::input_options::PackageManager;
+use crate::input_options::{
+    Alias, Aliases, Extensions, IndexFiles, MainField, MainFields, PackageManager,
+};
 use crate::path_ext::*;
 use fnv::{FnvHashMap, FnvHashSet};
 use indoc::indoc;
-use matches::assert_matches;
+use matches::{assert_matches, matches};
 use serde_json;
+use std::ffi::OsString;
 use std::io::{self, Write};
 use std::path::Path;
 use std::{fs, process};
@@ -231,6 +234,7 @@ fn assert_resolves_bower(context: &str, from: &str, to: Option<&str>) {
         package_manager: PackageManager::Bower,
         external,
         forced_npm_deps: FnvHashSet::default(),
+        ..Default::default()
     };
     assert_resolves_with_options(context, from, to, Some(&input_options));
 }
@@ -1205,6 +1209,23 @@ where
         Some("resolve/node_modules/n-mod-main-nesting-rel/subdir/inner-main.js"),
     );
 
+    assert_resolves(
+        ctx,
+        "@scope/n-scoped",
+        Some("resolve/node_modules/@scope/n-scoped/main.js"),
+    );
+    assert_resolves(
+        ctx,
+        "@scope/n-scoped/other-file",
+        Some("resolve/node_modules/@scope/n-scoped/other-file.js"),
+    );
+    assert_resolves(
+        ctx,
+        "@scope/n-scoped-other",
+        Some("resolve/node_modules/@scope/n-scoped-other/index.js"),
+    );
+    assert_resolves(ctx, "@scope/n-scoped-missing", None);
+
     assert_resolves(
         ctx,
         "n-mod-js-ext-bare",
@@ -2011,6 +2032,272 @@ where
     assert_resolves(ctx, "./4-dir/", Some("resolve-order/4-dir/index.json"));
 }
 
+#[test]
+fn test_resolve_order_custom() {
+    fn assert_resolves(from: &str, to: Option<&str>, extensions: &[&str], index_files: &[&str]) {
+        let options = InputOptions {
+            extensions: Extensions::from(extensions.to_vec()),
+            index_files: IndexFiles(index_files.iter().map(|&f| f.to_owned()).collect()),
+            ..Default::default()
+        };
+        assert_resolves_with_options("resolve-order/hypothetical.js", from, to, Some(&options));
+    }
+
+    // the defaults
+    let exts = &[".mjs", ".js", ".cjs", ".json"];
+    let index = &["index.mjs", "index.js", "index.json"];
+    assert_resolves("./5-file", Some("resolve-order/5-file.js"), exts, index);
+    assert_resolves("./5-dir", Some("resolve-order/5-dir/index.js"), exts, index);
+
+    // extensions are tried in the given order, with or without a leading dot
+    let exts = &["jsx", ".ts", ".js"];
+    assert_resolves("./5-file", Some("resolve-order/5-file.jsx"), exts, index);
+    let exts = &[".ts", ".jsx", ".js"];
+    assert_resolves("./5-file", Some("resolve-order/5-file.ts"), exts, index);
+    let exts = &[".json", ".js"];
+    assert_resolves("./1-file", Some("resolve-order/1-file"), exts, index);
+    assert_resolves("./2-file", Some("resolve-order/2-file.json"), exts, index);
+    assert_resolves("./1-dir", Some("resolve-order/1-dir.json"), exts, index);
+    assert_resolves("./5-file", Some("resolve-order/5-file.js"), exts, index);
+    assert_resolves("./3-file", Some("resolve-order/3-file.json"), exts, index);
+    let exts = &[".js"];
+    assert_resolves("./3-file", None, exts, index);
+    assert_resolves("./3-dir", Some("resolve-order/3-dir/index.js"), exts, index);
+    assert_resolves("./5-dir/main", None, exts, index);
+    let exts = &[".jsx"];
+    assert_resolves(
+        "./5-dir/main",
+        Some("resolve-order/5-dir/main.jsx"),
+        exts,
+        index,
+    );
+
+    // ...and so are index files
+    let exts = &[".mjs", ".js", ".cjs", ".json"];
+    let index = &["index.json", "index.js"];
+    assert_resolves(
+        "./1-dir/",
+        Some("resolve-order/1-dir/index.json"),
+        exts,
+        index,
+    );
+    assert_resolves(
+        "./3-dir",
+        Some("resolve-order/3-dir/index.json"),
+        exts,
+        index,
+    );
+    assert_resolves("./5-dir", Some("resolve-order/5-dir/index.js"), exts, index);
+    let index = &["index.ts", "index.js"];
+    assert_resolves("./5-dir", Some("resolve-order/5-dir/index.ts"), exts, index);
+    assert_resolves("./4-dir", None, exts, index);
+    assert_resolves(
+        "./2-dir/",
+        Some("resolve-order/2-dir/index.js"),
+        exts,
+        index,
+    );
+    // a file still wins over a directory
+    assert_resolves("./2-dir", Some("resolve-order/2-dir.json"), exts, index);
+}
+
+#[test]
+fn test_aliases() {
+    fn assert_resolves(from: &str, to: Option<&str>, aliases: &[(&str, &str)]) {
+        let base = fixture_path().join("alias");
+        let options = InputOptions {
+            aliases: Aliases::new(
+                aliases
+                    .iter()
+                    .map(|&(from, to)| Alias::new(from, to, &base))
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+        assert_resolves_with_options("alias/src/hypothetical.js", from, to, Some(&options));
+    }
+
+    // package names match exactly and as a prefix of their subpaths
+    let aliases = &[("fake-pkg", "real-pkg")];
+    assert_resolves(
+        "fake-pkg",
+        Some("alias/node_modules/real-pkg/index.js"),
+        aliases,
+    );
+    assert_resolves(
+        "fake-pkg/sub",
+        Some("alias/node_modules/real-pkg/sub.js"),
+        aliases,
+    );
+    assert_resolves("fake-pkg-2", None, aliases);
+    assert_resolves(
+        "real-pkg/sub",
+        Some("alias/node_modules/real-pkg/sub.js"),
+        aliases,
+    );
+
+    // ...but only exactly with a trailing $
+    let aliases = &[("fake-pkg$", "real-pkg/sub")];
+    assert_resolves(
+        "fake-pkg",
+        Some("alias/node_modules/real-pkg/sub.js"),
+        aliases,
+    );
+    assert_resolves("fake-pkg/sub", None, aliases);
+
+    // a trailing / matches anything it prefixes, and relative targets are resolved against the base
+    let aliases = &[("~/", "./src/")];
+    assert_resolves("~/utils/format", Some("alias/src/utils/format.js"), aliases);
+    assert_resolves("~/utils/missing", None, aliases);
+    let aliases = &[("@utils", "./src/utils")];
+    assert_resolves("@utils/format", Some("alias/src/utils/format.js"), aliases);
+
+    // the most specific alias wins
+    let aliases = &[("fake-pkg", "real-pkg"), ("fake-pkg/sub", "other-pkg")];
+    assert_resolves(
+        "fake-pkg/sub",
+        Some("alias/node_modules/other-pkg/index.js"),
+        aliases,
+    );
+    assert_resolves(
+        "fake-pkg",
+        Some("alias/node_modules/real-pkg/index.js"),
+        aliases,
+    );
+
+    // aliases chain, but each applies at most once
+    let aliases = &[("a-pkg", "b-pkg"), ("b-pkg", "real-pkg")];
+    assert_resolves(
+        "a-pkg/sub",
+        Some("alias/node_modules/real-pkg/sub.js"),
+        aliases,
+    );
+    let aliases = &[("real-pkg", "real-pkg/dist/real-pkg")];
+    assert_resolves(
+        "real-pkg",
+        Some("alias/node_modules/real-pkg/dist/real-pkg.js"),
+        aliases,
+    );
+}
+
+#[test]
+fn test_alias_cycles() {
+    let base = fixture_path().join("alias");
+    let context = base.join("src/hypothetical.js");
+    let resolver = Resolver::new(InputOptions {
+        aliases: Aliases::new(vec![
+            Alias::new("a-pkg", "b-pkg", &base),
+            Alias::new("b-pkg", "c-pkg", &base),
+            Alias::new("c-pkg", "a-pkg", &base),
+        ]),
+        ..Default::default()
+    });
+
+    match resolver.resolve(&context, "b-pkg/sub") {
+        Err(CliError::AliasCycle { chain, .. }) => {
+            assert_eq!(chain, &["b-pkg/sub", "c-pkg/sub", "a-pkg/sub", "b-pkg/sub"]);
+        }
+        other => panic!("expected AliasCycle, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_not_found_suggestions() {
+    let base = fixture_path().join("suggest");
+    let context = base.join("hypothetical.js");
+    let resolver = Resolver::new(InputOptions::default());
+    let not_found = |name: &str| match resolver.resolve(&context, name) {
+        Err(CliError::ModuleNotFound {
+            searched,
+            tried,
+            suggestions,
+            ..
+        }) => (searched, tried, suggestions),
+        other => panic!("expected ModuleNotFound for {}, got {:?}", name, other),
+    };
+
+    let (searched, tried, suggestions) = not_found("./widget");
+    assert_eq!(searched, std::slice::from_ref(&base));
+    assert!(tried.contains(&base.join("widget.js")));
+    assert_eq!(suggestions, &["'./Widget' (differs in case)"]);
+
+    let (_, _, suggestions) = not_found("./styles");
+    assert_eq!(suggestions, &["'./styles.ts' (different extension)"]);
+    let (_, _, suggestions) = not_found("./util.mjs");
+    assert_eq!(suggestions, &["'./util.js' (different extension)"]);
+    let (_, _, suggestions) = not_found("./formater");
+    assert_eq!(suggestions, &["'./formatter' (similar name)"]);
+
+    let (searched, tried, suggestions) = not_found("lodahs");
+    assert_eq!(searched[0], base.join("node_modules"));
+    assert!(!tried.is_empty());
+    assert_eq!(suggestions, &["'lodash' (similar name)"]);
+    let (_, _, suggestions) = not_found("lodash/indx");
+    assert_eq!(suggestions, &["'lodash/index' (similar name)"]);
+
+    let (_, _, suggestions) = not_found("bower-only");
+    assert_eq!(suggestions.len(), 1);
+    assert!(suggestions[0].starts_with("'bower-only' is in "));
+    assert!(suggestions[0].ends_with("use --for-bower to resolve packages from there"));
+}
+
+#[test]
+fn test_strict_case() {
+    let base = fixture_path().join("suggest");
+    let context = base.join("hypothetical.js");
+    let resolver = Resolver::new(InputOptions {
+        strict_case: true,
+        ..Default::default()
+    });
+    assert_eq!(
+        resolver.resolve(&context, "./Widget").unwrap(),
+        Resolved::Normal(base.join("Widget.js"))
+    );
+    assert_eq!(
+        resolver.resolve(&context, "lodash").unwrap(),
+        Resolved::Normal(base.join("node_modules/lodash/index.js"))
+    );
+
+    // what a case-insensitive filesystem would list
+    let list = |resolver: &Resolver, dir: &Path, entries: &[&str]| {
+        resolver.cache.dir_entries.insert(
+            dir,
+            Some(Arc::new(entries.iter().map(OsString::from).collect())),
+        );
+    };
+
+    let resolver = Resolver::new(InputOptions {
+        strict_case: true,
+        ..Default::default()
+    });
+    list(&resolver, &base, &["widget.js", "node_modules"]);
+    match resolver.resolve(&context, "./Widget") {
+        Err(CliError::CaseMismatch {
+            context: Some(error_context),
+            path,
+            actual,
+        }) => {
+            assert_eq!(error_context, context);
+            assert_eq!(path, base.join("Widget.js"));
+            assert_eq!(actual, base.join("widget.js"));
+        }
+        other => panic!("expected CaseMismatch, got {:?}", other),
+    }
+
+    list(&resolver, &base.join("node_modules"), &["Lodash"]);
+    match resolver.resolve(&context, "lodash") {
+        Err(CliError::CaseMismatch { actual, .. }) => {
+            assert_eq!(actual, base.join("node_modules/Lodash/index.js"));
+        }
+        other => panic!("expected CaseMismatch, got {:?}", other),
+    }
+
+    // only checked when asked for
+    let resolver = Resolver::new(InputOptions::default());
+    list(&resolver, &base, &["widget.js"]);
+    assert!(resolver.resolve(&context, "./Widget").is_ok());
+}
+
 fn test_resolve_unicode_with<F>(mut assert_resolves: F)
 where
     F: FnMut(&str, &str, Option<&str>),
@@ -2158,6 +2445,679 @@ fn test_resolve_browser() {
     test_browser_with(assert_resolves);
 }
 
+fn test_workspaces_with<F>(mut assert_resolves: F)
+where
+    F: FnMut(&str, &str, Option<&str>),
+{
+    let ctx = "workspaces/packages/app/index.js";
+    assert_resolves(ctx, "ws-lib", Some("workspaces/packages/lib/lib.js"));
+    assert_resolves(ctx, "ws-lib/util", Some("workspaces/packages/lib/util.js"));
+    assert_resolves(ctx, "ws-lib/missing", None);
+    assert_resolves(
+        ctx,
+        "ws-exported/feature",
+        Some("workspaces/packages/exported/src/feature.js"),
+    );
+    assert_resolves(
+        ctx,
+        "ws-deep",
+        Some("workspaces/tools/nested/deep/index.js"),
+    );
+    // excluded by "!packages/ignored"
+    assert_resolves(ctx, "ws-ignored", None);
+    // unknown names still aren't found
+    assert_resolves(ctx, "ws-missing", None);
+
+    let ctx = "workspaces/tools/nested/deep/index.js";
+    assert_resolves(ctx, "@ws/app", Some("workspaces/packages/app/index.js"));
+    assert_resolves(ctx, "ws-lib", Some("workspaces/packages/lib/lib.js"));
+
+    // only inside the workspace root
+    assert_resolves("resolve/hypothetical.js", "ws-lib", None);
+}
+
+#[test]
+fn test_resolve_workspaces() {
+    test_workspaces_with(assert_resolves);
+}
+
+#[test]
+fn test_workspaces_exports_errors() {
+    let mut context = fixture_path();
+    context.append_resolving("workspaces/packages/app/index.js");
+    let resolver = Resolver::new(InputOptions::default());
+    assert_matches!(
+        resolver.resolve(&context, "ws-exported/src/feature.js"),
+        Err(CliError::SubpathNotExported { .. })
+    );
+}
+
+#[test]
+fn test_resolve_pnp() {
+    let options = InputOptions {
+        package_manager: PackageManager::Pnp,
+        ..Default::default()
+    };
+    let assert_resolves = |ctx: &str, from: &str, to: Option<&str>| {
+        assert_resolves_with_options(ctx, from, to, Some(&options))
+    };
+    let dep = "pnp/.yarn/unplugged/dep-npm-1.0.0-abc/node_modules/dep";
+    let inner = "pnp/.yarn/unplugged/inner-npm-2.0.0-def/node_modules/inner";
+
+    let ctx = "pnp/src/index.js";
+    assert_resolves(ctx, "dep", Some(&format!("{}/lib.js", dep)));
+    assert_resolves(ctx, "dep/sub", Some(&format!("{}/sub.js", dep)));
+    assert_resolves(ctx, "dep/missing", None);
+    assert_resolves(ctx, "alias-name", Some(&format!("{}/lib.js", dep)));
+    assert_resolves(ctx, "./index", Some("pnp/src/index.js"));
+
+    // each package sees its own dependencies...
+    let ctx = &format!("{}/lib.js", dep);
+    assert_resolves(ctx, "inner", Some(&format!("{}/index.js", inner)));
+    assert_resolves(ctx, "./sub", Some(&format!("{}/sub.js", dep)));
+    // ...and the top-level ones, unless they're excluded from the fallback
+    let ctx = &format!("{}/index.js", inner);
+    assert_resolves(ctx, "dep", Some(&format!("{}/lib.js", dep)));
+
+    // without --pnp, there's no node_modules to find packages in
+    assert_resolves_with_options("pnp/src/index.js", "dep", None, None);
+}
+
+#[test]
+fn test_pnp_errors() {
+    let resolver = Resolver::new(InputOptions {
+        package_manager: PackageManager::Pnp,
+        ..Default::default()
+    });
+    let context = |path: &str| {
+        let mut context = fixture_path();
+        context.append_resolving(path);
+        context
+    };
+
+    match resolver.resolve(&context("pnp/src/index.js"), "inner") {
+        Err(CliError::PnpUndeclaredDependency { issuer, name, .. }) => {
+            assert_eq!(issuer, "the top-level package");
+            assert_eq!(name, "inner");
+        }
+        other => panic!("expected PnpUndeclaredDependency, got {:?}", other),
+    }
+    let strict = "pnp/.yarn/unplugged/strict-npm-1.0.0-ghi/node_modules/strict/index.js";
+    match resolver.resolve(&context(strict), "dep") {
+        Err(CliError::PnpUndeclaredDependency { issuer, .. }) => assert_eq!(issuer, "strict"),
+        other => panic!("expected PnpUndeclaredDependency, got {:?}", other),
+    }
+    let dep = "pnp/.yarn/unplugged/dep-npm-1.0.0-abc/node_modules/dep/lib.js";
+    assert_matches!(
+        resolver.resolve(&context(dep), "missing-peer"),
+        Err(CliError::PnpMissingPeerDependency { .. })
+    );
+    match resolver.resolve(&context("pnp/src/index.js"), "zipped") {
+        Err(CliError::PnpZipArchive { archive, .. }) => assert_eq!(
+            archive,
+            context("pnp/.yarn/cache/zipped-npm-1.0.0-xyz-0123456789.zip")
+        ),
+        other => panic!("expected PnpZipArchive, got {:?}", other),
+    }
+
+    // files outside a PnP project use node_modules
+    let mut expected = fixture_path();
+    expected.append_resolving("resolve/node_modules/n-file-and-dir.js");
+    assert_eq!(
+        resolver
+            .resolve(&context("resolve/hypothetical.js"), "n-file-and-dir")
+            .unwrap(),
+        Resolved::Normal(expected)
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlinks() {
+    use std::os::unix::fs::symlink;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let base = fs::canonicalize(tmp.path()).unwrap();
+    for file in &[
+        "real/lib/index.js",
+        "real/node_modules/dep/index.js",
+        "app/src/index.js",
+        "app/node_modules/dep/index.js",
+    ] {
+        let path = base.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "").unwrap();
+    }
+    symlink("../../real/lib", base.join("app/node_modules/linked")).unwrap();
+    symlink("index.js", base.join("app/src/link.js")).unwrap();
+    let context = base.join("app/src/index.js");
+
+    // resolved paths are real paths by default...
+    let resolver = Resolver::new(InputOptions::default());
+    let linked = resolver.resolve(&context, "linked").unwrap();
+    assert_eq!(linked, Resolved::Normal(base.join("real/lib/index.js")));
+    assert_eq!(
+        resolver.resolve(&context, "./link").unwrap(),
+        Resolved::Normal(context.clone())
+    );
+    assert_eq!(
+        resolver
+            .resolve(&base.join("real/lib/index.js"), "dep")
+            .unwrap(),
+        Resolved::Normal(base.join("real/node_modules/dep/index.js"))
+    );
+    assert_eq!(
+        resolver
+            .resolve_main(base.join("app"), "./src/link")
+            .unwrap(),
+        Resolved::Normal(context.clone())
+    );
+
+    // ...unless symlinks are preserved
+    let resolver = Resolver::new(InputOptions {
+        preserve_symlinks: true,
+        ..Default::default()
+    });
+    let linked = base.join("app/node_modules/linked/index.js");
+    assert_eq!(
+        resolver.resolve(&context, "linked").unwrap(),
+        Resolved::Normal(linked.clone())
+    );
+    assert_eq!(
+        resolver.resolve(&context, "./link").unwrap(),
+        Resolved::Normal(base.join("app/src/link.js"))
+    );
+    assert_eq!(
+        resolver.resolve(&linked, "dep").unwrap(),
+        Resolved::Normal(base.join("app/node_modules/dep/index.js"))
+    );
+}
+
+#[test]
+fn test_trace() {
+    let base = fixture_path().join("alias");
+    let resolver = Resolver::new(InputOptions {
+        aliases: Aliases::new(vec![Alias::new("fake-pkg", "real-pkg", &base)]),
+        ..Default::default()
+    })
+    .with_trace();
+    let context = base.join("src/hypothetical.js");
+    let pkg = base.join("node_modules/real-pkg");
+
+    assert_eq!(
+        resolver.resolve(&context, "fake-pkg").unwrap(),
+        Resolved::Normal(pkg.join("index.js"))
+    );
+    let candidate = |path: PathBuf, outcome| TraceStep::Candidate { path, outcome };
+    let missing = |path: &str| candidate(base.join(path), Outcome::Missing);
+    let (no_manifests, trace): (Vec<_>, Vec<_>) = resolver
+        .take_trace()
+        .into_iter()
+        .partition(|step| matches!(step, TraceStep::NoManifest { .. }));
+    // manifests that would change the result by appearing
+    for path in &[
+        "src/package.json",
+        "package.json",
+        "node_modules/real-pkg/package.json",
+    ] {
+        assert!(no_manifests.contains(&TraceStep::NoManifest {
+            path: base.join(path)
+        }));
+    }
+    assert_eq!(
+        trace,
+        vec![
+            TraceStep::Alias {
+                from: "fake-pkg".to_owned(),
+                to: "real-pkg".to_owned(),
+            },
+            missing("src/node_modules/real-pkg"),
+            missing("src/node_modules/real-pkg.mjs"),
+            missing("src/node_modules/real-pkg.js"),
+            missing("src/node_modules/real-pkg.cjs"),
+            missing("src/node_modules/real-pkg.json"),
+            missing("src/node_modules/real-pkg/index.mjs"),
+            missing("src/node_modules/real-pkg/index.js"),
+            missing("src/node_modules/real-pkg/index.json"),
+            missing("node_modules/real-pkg"),
+            missing("node_modules/real-pkg.mjs"),
+            missing("node_modules/real-pkg.js"),
+            missing("node_modules/real-pkg.cjs"),
+            missing("node_modules/real-pkg.json"),
+            missing("node_modules/real-pkg/index.mjs"),
+            candidate(pkg.join("index.js"), Outcome::Found),
+        ]
+    );
+
+    // manifests and substitutions
+    let mut context = fixture_path();
+    context.append_resolving("browser/replace-dependency/hypothetical.js");
+    let mut dir = fixture_path();
+    dir.append_resolving("browser/replace-dependency");
+    resolver.resolve(&context, "dependency").unwrap();
+    let trace = resolver.take_trace();
+    assert_eq!(
+        trace[..2],
+        [
+            TraceStep::Manifest {
+                path: dir.join("package.json"),
+                main: dir.join("index.js"),
+                main_field: Some(MainField::Main),
+            },
+            TraceStep::Substitution {
+                from: "dependency".to_owned(),
+                to: Some(
+                    dir.join("browser-dependency.js")
+                        .to_string_lossy()
+                        .into_owned()
+                ),
+                manifest: dir.join("package.json"),
+            },
+        ]
+    );
+    assert_eq!(
+        trace.last(),
+        Some(&candidate(
+            dir.join("browser-dependency.js"),
+            Outcome::Found
+        ))
+    );
+
+    // the manifests the names of a workspace's packages come from
+    let mut workspace = fixture_path();
+    workspace.append_resolving("workspaces");
+    resolver
+        .resolve(&workspace.join("packages/app/index.js"), "ws-lib")
+        .unwrap();
+    let manifests: Vec<_> = resolver
+        .take_trace()
+        .into_iter()
+        .filter_map(|step| match step {
+            TraceStep::Manifest { path, .. } => Some(path),
+            _ => None,
+        })
+        .collect();
+    for path in &[
+        "package.json",
+        "packages/exported/package.json",
+        "tools/nested/deep/package.json",
+    ] {
+        assert!(manifests.contains(&workspace.join(path)));
+    }
+    assert!(!manifests.contains(&workspace.join("packages/ignored/package.json")));
+
+    // nothing is recorded unless asked for
+    let resolver = Resolver::new(InputOptions::default());
+    resolver.resolve(&context, "dependency").unwrap();
+    assert_eq!(resolver.take_trace(), vec![]);
+}
+
+#[test]
+fn test_browser_cycles() {
+    let mut context = fixture_path();
+    context.append_resolving("browser/replace-cycle/hypothetical.js");
+    let mut manifest = fixture_path();
+    manifest.append_resolving("browser/replace-cycle/package.json");
+    let resolver = Resolver::new(InputOptions::default());
+
+    // substitutions chain...
+    let mut expected = fixture_path();
+    expected.append_resolving("browser/replace-cycle/node_modules/d/index.js");
+    assert_eq!(
+        resolver.resolve(&context, "e").unwrap(),
+        Resolved::Normal(expected)
+    );
+
+    // ...but never back to a module they've already replaced
+    let assert_cycle = |from: &str, expected_chain: &[&str]| match resolver.resolve(&context, from)
+    {
+        Err(CliError::SubstitutionCycle {
+            manifest: m, chain, ..
+        }) => {
+            assert_eq!(m, manifest);
+            assert_eq!(chain, expected_chain);
+        }
+        other => panic!("expected SubstitutionCycle, got {:?}", other),
+    };
+    assert_cycle("a", &["a", "b", "a"]);
+    assert_cycle("b/index.js", &["b/index.js", "a/index.js", "b/index.js"]);
+    assert_cycle("self", &["self", "self/browser"]);
+}
+
+fn test_exports_with<F>(mut assert_resolves: F)
+where
+    F: FnMut(&str, &str, Option<&str>),
+{
+    let ctx = "exports/hypothetical.js";
+    assert_resolves(
+        ctx,
+        "exports-string",
+        Some("exports/node_modules/exports-string/main.js"),
+    );
+    assert_resolves(ctx, "exports-string/other.js", None);
+    assert_resolves(ctx, "exports-string/legacy.js", None);
+
+    assert_resolves(
+        ctx,
+        "exports-subpaths",
+        Some("exports/node_modules/exports-subpaths/lib/index.js"),
+    );
+    assert_resolves(
+        ctx,
+        "exports-subpaths/feature",
+        Some("exports/node_modules/exports-subpaths/lib/feature.js"),
+    );
+    assert_resolves(
+        ctx,
+        "exports-subpaths/package.json",
+        Some("exports/node_modules/exports-subpaths/package.json"),
+    );
+    assert_resolves(
+        ctx,
+        "exports-subpaths/utils/format",
+        Some("exports/node_modules/exports-subpaths/lib/utils/format.js"),
+    );
+    assert_resolves(ctx, "exports-subpaths/utils/private/secret", None);
+    assert_resolves(ctx, "exports-subpaths/lib/internal.js", None);
+    assert_resolves(ctx, "exports-subpaths/feature.js", None);
+    assert_resolves(ctx, "exports-subpaths/escape", None);
+
+    assert_resolves(
+        ctx,
+        "exports-conditions",
+        Some("exports/node_modules/exports-conditions/browser.mjs"),
+    );
+    assert_resolves(
+        ctx,
+        "exports-conditions/require-only",
+        Some("exports/node_modules/exports-conditions/require.js"),
+    );
+    assert_resolves(ctx, "exports-conditions/node-only", None);
+    assert_resolves(
+        ctx,
+        "exports-conditions/fallback",
+        Some("exports/node_modules/exports-conditions/fallback.js"),
+    );
+    assert_resolves(ctx, "exports-conditions/excluded", None);
+
+    assert_resolves(
+        ctx,
+        "exports-sugar",
+        Some("exports/node_modules/exports-sugar/esm.mjs"),
+    );
+
+    assert_resolves(
+        ctx,
+        "@scope/exports-scoped",
+        Some("exports/node_modules/@scope/exports-scoped/index.js"),
+    );
+    assert_resolves(
+        ctx,
+        "@scope/exports-scoped/sub",
+        Some("exports/node_modules/@scope/exports-scoped/sub/index.js"),
+    );
+    assert_resolves(ctx, "@scope/exports-scoped/sub/index.js", None);
+
+    assert_resolves(ctx, "exports-missing-target", None);
+}
+
+#[test]
+fn test_resolve_exports() {
+    test_exports_with(assert_resolves);
+}
+
+#[test]
+fn test_exports_errors() {
+    let mut context = fixture_path();
+    context.append_resolving("exports/hypothetical.js");
+    let resolver = Resolver::new(InputOptions::default());
+
+    match resolver.resolve(&context, "@scope/exports-scoped/sub/index.js") {
+        Err(CliError::SubpathNotExported {
+            package, subpath, ..
+        }) => {
+            assert_eq!(package, "@scope/exports-scoped");
+            assert_eq!(subpath, "./sub/index.js");
+        }
+        other => panic!("expected SubpathNotExported, got {:?}", other),
+    }
+    assert_matches!(
+        resolver.resolve(&context, "exports-missing-target"),
+        Err(CliError::ModuleNotFound { .. })
+    );
+}
+
+fn test_imports_with<F>(mut assert_resolves: F)
+where
+    F: FnMut(&str, &str, Option<&str>),
+{
+    for ctx in &[
+        "imports/hypothetical.js",
+        "imports/src/deep/hypothetical.js",
+    ] {
+        assert_resolves(ctx, "#utils", Some("imports/src/utils.js"));
+        assert_resolves(ctx, "#env", Some("imports/src/env-browser.js"));
+        assert_resolves(ctx, "#internal/a", Some("imports/src/internal/a.js"));
+        assert_resolves(ctx, "#internal/private/b", None);
+        assert_resolves(
+            ctx,
+            "#dep",
+            Some("imports/node_modules/imports-dep/index.js"),
+        );
+        assert_resolves(
+            ctx,
+            "#dep/extra",
+            Some("imports/node_modules/imports-dep/extra.js"),
+        );
+        assert_resolves(ctx, "#missing", None);
+        assert_resolves(ctx, "#undefined", None);
+        assert_resolves(ctx, "#own", None);
+    }
+
+    let ctx = "imports/node_modules/imports-dep/hypothetical.js";
+    assert_resolves(ctx, "#own", Some("imports/node_modules/imports-dep/own.js"));
+    assert_resolves(ctx, "#utils", None);
+}
+
+#[test]
+fn test_resolve_imports() {
+    test_imports_with(assert_resolves);
+}
+
+#[test]
+fn test_imports_errors() {
+    let mut context = fixture_path();
+    context.append_resolving("imports/hypothetical.js");
+    let resolver = Resolver::new(InputOptions::default());
+
+    match resolver.resolve(&context, "#undefined") {
+        Err(CliError::ImportNotDefined { name, .. }) => assert_eq!(name, "#undefined"),
+        other => panic!("expected ImportNotDefined, got {:?}", other),
+    }
+    assert_matches!(
+        resolver.resolve(&context, "#missing"),
+        Err(CliError::ModuleNotFound { .. })
+    );
+}
+
+#[test]
+fn test_main_fields() {
+    fn assert_resolves(from: &str, to: &str, main_fields: &[MainField]) {
+        let options = InputOptions {
+            main_fields: MainFields(main_fields.to_vec()),
+            ..Default::default()
+        };
+        assert_resolves_with_options(
+            "main-fields/hypothetical.js",
+            from,
+            Some(to),
+            Some(&options),
+        );
+    }
+    use MainField::*;
+
+    let default = &MainFields::default().0[..];
+    let nm = "main-fields/node_modules";
+    assert_resolves(
+        "all-fields",
+        &format!("{}/all-fields/browser.js", nm),
+        default,
+    );
+    assert_resolves(
+        "all-fields",
+        &format!("{}/all-fields/module.mjs", nm),
+        &[Module, Browser, Main],
+    );
+    assert_resolves(
+        "all-fields",
+        &format!("{}/all-fields/browser.js", nm),
+        &[Browser, Module, Main],
+    );
+    assert_resolves("all-fields", &format!("{}/all-fields/main.js", nm), &[Main]);
+    assert_resolves("all-fields", &format!("{}/all-fields/index.js", nm), &[]);
+
+    assert_resolves(
+        "module-and-main",
+        &format!("{}/module-and-main/main.js", nm),
+        default,
+    );
+    assert_resolves(
+        "module-and-main",
+        &format!("{}/module-and-main/esm/index.mjs", nm),
+        &[Browser, Module, Main],
+    );
+
+    assert_resolves(
+        "main-only",
+        &format!("{}/main-only/main.js", nm),
+        &[Browser, Module, Main],
+    );
+    assert_resolves(
+        "main-only",
+        &format!("{}/main-only/index.js", nm),
+        &[Module],
+    );
+}
+
+#[test]
+fn test_resolve_cjs() {
+    let ctx = "module-type/hypothetical.js";
+    assert_resolves(ctx, "./helper", Some("module-type/helper.cjs"));
+    assert_resolves(ctx, "./helper.cjs", Some("module-type/helper.cjs"));
+    assert_resolves(
+        ctx,
+        "esm-pkg/legacy",
+        Some("module-type/node_modules/esm-pkg/legacy.cjs"),
+    );
+}
+
+#[test]
+fn test_package_type() {
+    let resolver = Resolver::new(InputOptions::default());
+    let package_type = |path: &str| {
+        let mut module = fixture_path();
+        module.append_resolving(path);
+        resolver.package_type(&module).unwrap()
+    };
+    assert_eq!(
+        package_type("module-type/node_modules/esm-pkg/index.js"),
+        PackageType::Module
+    );
+    assert_eq!(
+        package_type("module-type/node_modules/esm-pkg/legacy.cjs"),
+        PackageType::Module
+    );
+    assert_eq!(package_type("module-type/index.js"), PackageType::CommonJs);
+}
+
+#[test]
+fn test_scoped_packages() {
+    let ctx = "resolve/hypothetical.js";
+    let scoped = "resolve/node_modules/@scope/n-scoped";
+    let external = InputOptions {
+        external: vec!["@scope/n-scoped".to_owned()].into_iter().collect(),
+        ..Default::default()
+    };
+    let resolver = Resolver::new(external);
+    let mut context = fixture_path();
+    context.append_resolving(ctx);
+    assert_eq!(
+        resolver.resolve(&context, "@scope/n-scoped").unwrap(),
+        Resolved::External
+    );
+    assert_eq!(
+        resolver
+            .resolve(&context, "@scope/n-scoped/other-file.js")
+            .unwrap(),
+        Resolved::External
+    );
+    let mut other = fixture_path();
+    other.append_resolving("resolve/node_modules/@scope/n-scoped-other/index.js");
+    assert_eq!(
+        resolver.resolve(&context, "@scope/n-scoped-other").unwrap(),
+        Resolved::Normal(other)
+    );
+
+    let ctx = "resolve/scoped-browser/hypothetical.js";
+    let mut context = fixture_path();
+    context.append_resolving(ctx);
+    assert_resolves(
+        ctx,
+        "@scope/n-scoped",
+        Some("resolve/node_modules/@scope/n-scoped-other/index.js"),
+    );
+    assert_resolves(
+        ctx,
+        "@scope/n-scoped/other-file",
+        Some("resolve/node_modules/@scope/n-scoped-other/other-file.js"),
+    );
+    assert_eq!(
+        Resolver::new(InputOptions::default())
+            .resolve(&context, "@scope/n-scoped-ignored")
+            .unwrap(),
+        Resolved::Ignore
+    );
+
+    // no bower_components here, so scoped packages only resolve when forced through npm
+    let ctx = "resolve/hypothetical.js";
+    let bower = InputOptions {
+        package_manager: PackageManager::Bower,
+        ..Default::default()
+    };
+    let forced = InputOptions {
+        package_manager: PackageManager::Bower,
+        forced_npm_deps: vec!["@scope/n-scoped".to_owned()].into_iter().collect(),
+        ..Default::default()
+    };
+    assert_resolves_with_options(ctx, "@scope/n-scoped", None, Some(&bower));
+    assert_resolves_with_options(
+        ctx,
+        "@scope/n-scoped",
+        Some(&format!("{}/main.js", scoped)),
+        Some(&forced),
+    );
+    assert_resolves_with_options(
+        ctx,
+        "@scope/n-scoped/other-file",
+        Some(&format!("{}/other-file.js", scoped)),
+        Some(&forced),
+    );
+    assert_resolves_with_options(ctx, "@scope/n-scoped-other", None, Some(&forced));
+}
+
+#[test]
+fn test_split_package_name() {
+    assert_eq!(split_package_name("pkg"), ("pkg", ""));
+    assert_eq!(split_package_name("pkg/"), ("pkg", "/"));
+    assert_eq!(split_package_name("pkg/a/b.js"), ("pkg", "/a/b.js"));
+    assert_eq!(split_package_name("@scope/pkg"), ("@scope/pkg", ""));
+    assert_eq!(
+        split_package_name("@scope/pkg/a/b.js"),
+        ("@scope/pkg", "/a/b.js")
+    );
+    assert_eq!(split_package_name("@scope"), ("@scope", ""));
+}
+
 #[test]
 fn test_external() {
     fn fixture_path() -> PathBuf {
@@ -2190,11 +3150,13 @@ fn test_external() {
             .into_iter()
             .collect(),
         forced_npm_deps: FnvHashSet::default(),
+        ..Default::default()
     };
     let non = InputOptions {
         package_manager: PackageManager::Npm,
         external: Default::default(),
         forced_npm_deps: FnvHashSet::default(),
+        ..Default::default()
     };
 
     let ctx = "resolve/hypothetical.js";
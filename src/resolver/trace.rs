@@ -0,0 +1,98 @@
+use crate::input_options::MainField;
+use serde::Serialize;
+use std::fmt;
+use std::path::PathBuf;
+
+// what the resolver did to resolve a require, in order; see `scrumple resolve`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "step", rename_all = "camelCase")]
+pub enum TraceStep {
+    #[serde(rename_all = "camelCase")]
+    Alias { from: String, to: String },
+    // a browser field substitution for a module name; `to` is None when it's ignored
+    #[serde(rename_all = "camelCase")]
+    Substitution {
+        from: String,
+        to: Option<String>,
+        manifest: PathBuf,
+    },
+    #[serde(rename_all = "camelCase")]
+    Manifest {
+        path: PathBuf,
+        main: PathBuf,
+        main_field: Option<MainField>,
+    },
+    // a manifest that was looked for and isn't there, or can't be read; one appearing would change
+    // the result
+    #[serde(rename_all = "camelCase")]
+    NoManifest { path: PathBuf },
+    #[serde(rename_all = "camelCase")]
+    Candidate {
+        path: PathBuf,
+        #[serde(flatten)]
+        outcome: Outcome,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "outcome", rename_all = "camelCase")]
+pub enum Outcome {
+    Missing,
+    Found,
+    // by a browser field substitution
+    Ignored,
+    Replaced { to: PathBuf },
+}
+
+impl fmt::Display for TraceStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TraceStep::Alias { ref from, ref to } => write!(f, "alias '{}' -> '{}'", from, to),
+            TraceStep::Substitution {
+                ref from,
+                ref to,
+                ref manifest,
+            } => match *to {
+                Some(ref to) => write!(
+                    f,
+                    "substitute '{}' -> '{}' (browser field of {})",
+                    from,
+                    to,
+                    manifest.display()
+                ),
+                None => write!(
+                    f,
+                    "ignore '{}' (browser field of {})",
+                    from,
+                    manifest.display()
+                ),
+            },
+            TraceStep::Manifest {
+                ref path,
+                ref main,
+                main_field,
+            } => {
+                write!(f, "manifest {}, main {}", path.display(), main.display())?;
+                match main_field {
+                    Some(field) => write!(f, " (from {})", field),
+                    None => write!(f, " (default)"),
+                }
+            }
+            TraceStep::NoManifest { ref path } => write!(f, "no manifest {}", path.display()),
+            TraceStep::Candidate {
+                ref path,
+                ref outcome,
+            } => {
+                write!(f, "try {}: ", path.display())?;
+                match *outcome {
+                    Outcome::Missing => write!(f, "missing"),
+                    Outcome::Found => write!(f, "found"),
+                    Outcome::Ignored => write!(f, "ignored by browser field"),
+                    Outcome::Replaced { ref to } => {
+                        write!(f, "replaced by browser field with {}", to.display())
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,420 @@
+use crate::bundler::Served;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{fs, thread};
+
+// where the client in live_reload.js listens for builds
+const EVENTS_PATH: &str = "/_scrumple/events";
+
+// the development server for `scrumple serve`. it serves the bundle and its source map from
+// memory, everything else from a directory, and tells the pages that load the bundle about each
+// build over an event stream. it's meant for localhost only: one thread per connection, GET and
+// HEAD only, and no keep-alive.
+#[derive(Debug)]
+pub struct Server {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    // where the bundle and its source map are served, as URL paths
+    bundle_path: String,
+    map_path: Option<String>,
+    static_dir: PathBuf,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    served: Served,
+    // the message if the last build failed, for pages that connect later
+    error: Option<String>,
+    clients: Vec<TcpStream>,
+}
+
+impl Server {
+    // `output` and `map_output` are the paths the bundle and its source map are served at
+    pub fn start(
+        addr: SocketAddr,
+        output: &str,
+        map_output: Option<&Path>,
+        static_dir: PathBuf,
+        served: Served,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            bundle_path: url_path(Path::new(output)),
+            map_path: map_output.map(url_path),
+            static_dir,
+            state: Mutex::new(State {
+                served,
+                error: None,
+                clients: Vec::new(),
+            }),
+        });
+        {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(_) => continue,
+                    };
+                    let shared = Arc::clone(&shared);
+                    thread::spawn(move || {
+                        let _ = shared.handle(stream);
+                    });
+                }
+            });
+        }
+        Ok(Server { addr, shared })
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}/", self.addr)
+    }
+
+    pub fn bundle_url(&self) -> String {
+        format!("http://{}{}", self.addr, self.shared.bundle_path)
+    }
+
+    // serves the new bundle, and reloads the pages that use it
+    pub fn succeeded(&self, served: Served) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.served = served;
+        state.error = None;
+        state.broadcast("success", "{}");
+    }
+
+    // shows `error` over the pages that use the bundle, which is left as it was
+    pub fn failed(&self, error: &str) {
+        let mut state = self.shared.state.lock().unwrap();
+        let data = serde_json::to_string(error).unwrap();
+        state.broadcast("failure", &data);
+        state.error = Some(data);
+    }
+}
+
+impl State {
+    // and forgets the clients that have gone away
+    fn broadcast(&mut self, event: &str, data: &str) {
+        self.clients
+            .retain_mut(|client| send_event(client, event, data).is_ok());
+    }
+}
+
+impl Shared {
+    fn handle(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        let (method, target) = {
+            let mut reader = BufReader::new(Read::by_ref(&mut stream).take(16 * 1024));
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line)?;
+            // the headers don't matter
+            let mut header = String::new();
+            loop {
+                header.clear();
+                if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                    break;
+                }
+            }
+            let mut parts = request_line.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some(method), Some(target)) => (method.to_owned(), target.to_owned()),
+                _ => return respond(&mut stream, "400 Bad Request", &[], b"bad request", false),
+            }
+        };
+        let head = match &*method {
+            "GET" => false,
+            "HEAD" => true,
+            _ => {
+                return respond(
+                    &mut stream,
+                    "405 Method Not Allowed",
+                    &[("Allow", "GET, HEAD")],
+                    b"method not allowed",
+                    false,
+                )
+            }
+        };
+        let raw_path = target.split(['?', '#']).next().unwrap();
+        let path = match percent_decode(raw_path) {
+            Some(path) if path.starts_with('/') => path,
+            _ => return respond(&mut stream, "400 Bad Request", &[], b"bad request", head),
+        };
+
+        if path == EVENTS_PATH && !head {
+            return self.subscribe(stream);
+        }
+        if path == self.bundle_path {
+            let bundle = Arc::clone(&self.state.lock().unwrap().served.bundle);
+            return respond(
+                &mut stream,
+                "200 OK",
+                &[("Content-Type", "application/javascript; charset=utf-8")],
+                &bundle,
+                head,
+            );
+        }
+        if self.map_path.as_ref() == Some(&path) {
+            let map = self.state.lock().unwrap().served.map.clone();
+            if let Some(map) = map {
+                return respond(
+                    &mut stream,
+                    "200 OK",
+                    &[("Content-Type", "application/json")],
+                    &map,
+                    head,
+                );
+            }
+        }
+
+        let file = match static_file(&self.static_dir, &path) {
+            Some(file) => file,
+            None => return respond(&mut stream, "404 Not Found", &[], b"not found", head),
+        };
+        if file.is_dir() {
+            if !path.ends_with('/') {
+                // so that relative URLs in its index.html work
+                let location = format!("{}/", raw_path);
+                return respond(
+                    &mut stream,
+                    "301 Moved Permanently",
+                    &[("Location", &location)],
+                    b"",
+                    head,
+                );
+            }
+            return self.serve_file(&mut stream, &file.join("index.html"), head);
+        }
+        self.serve_file(&mut stream, &file, head)
+    }
+
+    fn serve_file(&self, stream: &mut TcpStream, file: &Path, head: bool) -> io::Result<()> {
+        match fs::read(file) {
+            Ok(contents) => respond(
+                stream,
+                "200 OK",
+                &[("Content-Type", content_type(file))],
+                &contents,
+                head,
+            ),
+            Err(_) => respond(stream, "404 Not Found", &[], b"not found", head),
+        }
+    }
+
+    fn subscribe(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.write_all(
+            b"HTTP/1.1 200 OK\r\n\
+              Content-Type: text/event-stream\r\n\
+              Cache-Control: no-cache\r\n\
+              Access-Control-Allow-Origin: *\r\n\
+              Connection: keep-alive\r\n\
+              \r\n",
+        )?;
+        // so that one stuck page can't hold up the others
+        stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+        let mut state = self.state.lock().unwrap();
+        if let Some(ref error) = state.error {
+            send_event(&mut stream, "failure", error)?;
+        }
+        state.clients.push(stream);
+        Ok(())
+    }
+}
+
+fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    head: bool,
+) -> io::Result<()> {
+    let mut response = format!("HTTP/1.1 {}\r\n", status);
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str(&format!(
+        "Content-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        body.len()
+    ));
+    stream.write_all(response.as_bytes())?;
+    if !head {
+        stream.write_all(body)?;
+    }
+    stream.flush()
+}
+
+fn send_event(stream: &mut TcpStream, event: &str, data: &str) -> io::Result<()> {
+    write!(stream, "event: {}\ndata: {}\n\n", event, data)?;
+    stream.flush()
+}
+
+// the URL path a file is served at, relative to the root
+fn url_path(path: &Path) -> String {
+    let mut url = String::new();
+    for component in path.components() {
+        if let Component::Normal(part) = component {
+            url.push('/');
+            url.push_str(&part.to_string_lossy());
+        }
+    }
+    url
+}
+
+// the file in `dir` for a URL path, unless it would be outside it
+fn static_file(dir: &Path, path: &str) -> Option<PathBuf> {
+    let mut file = dir.to_owned();
+    for part in path.split('/') {
+        if part.is_empty() || part == "." {
+            continue;
+        }
+        let mut components = Path::new(part).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => file.push(part),
+            _ => return None,
+        }
+    }
+    Some(file)
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(byte) = iter.next() {
+        if byte == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn content_type(file: &Path) -> &'static str {
+    let extension = file
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("");
+    match &*extension.to_ascii_lowercase() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "js" | "mjs" | "cjs" => "application/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn get(server: &Server, path: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(server.addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_at(response.find("\r\n\r\n").unwrap());
+        let status = head.lines().next().unwrap()["HTTP/1.1 ".len()..].to_owned();
+        (status, body[4..].to_owned())
+    }
+
+    fn served(bundle: &str) -> Served {
+        Served {
+            bundle: Arc::new(bundle.as_bytes().to_vec()),
+            map: Some(Arc::new(b"{}".to_vec())),
+        }
+    }
+
+    #[test]
+    fn test_server() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("index.html"), "<script src=js/app.js>").unwrap();
+        fs::write(dir.path().join("sub/a b.css"), "a {}").unwrap();
+        let server = Server::start(
+            "127.0.0.1:0".parse().unwrap(),
+            "js/app.js",
+            Some(Path::new("js/app.js.map")),
+            dir.path().to_owned(),
+            served("one"),
+        )
+        .unwrap();
+
+        assert_eq!(server.bundle_url(), format!("{}js/app.js", server.url()));
+
+        let ok = |body: &str| ("200 OK".to_owned(), body.to_owned());
+        assert_eq!(get(&server, "/js/app.js?v=1"), ok("one"));
+        assert_eq!(get(&server, "/js/app.js.map"), ok("{}"));
+        assert_eq!(get(&server, "/"), ok("<script src=js/app.js>"));
+        assert_eq!(get(&server, "/sub/a%20b.css"), ok("a {}"));
+        assert_eq!(get(&server, "/sub").0, "301 Moved Permanently");
+        assert_eq!(get(&server, "/missing.js").0, "404 Not Found");
+        assert_eq!(get(&server, "/sub/../../etc/passwd").0, "404 Not Found");
+
+        server.succeeded(served("two"));
+        assert_eq!(get(&server, "/js/app.js"), ok("two"));
+    }
+
+    #[test]
+    fn test_server_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = Server::start(
+            "127.0.0.1:0".parse().unwrap(),
+            "bundle.js",
+            None,
+            dir.path().to_owned(),
+            served("one"),
+        )
+        .unwrap();
+        server.failed("too early");
+
+        let mut stream = TcpStream::connect(server.addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\n\r\n", EVENTS_PATH).unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut headers = String::new();
+        while !headers.ends_with("\r\n\r\n") {
+            reader.read_line(&mut headers).unwrap();
+        }
+        // so that a page from another origin can listen too
+        assert!(headers.contains("Access-Control-Allow-Origin: *\r\n"));
+        let mut next_event = || {
+            let mut event = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\n" && event.starts_with("event:") {
+                    return event;
+                }
+                if line.starts_with("event:") || line.starts_with("data:") {
+                    event.push_str(&line);
+                }
+            }
+        };
+
+        // the last build's error, as soon as it connects
+        assert_eq!(next_event(), "event: failure\ndata: \"too early\"\n");
+        // it's sent that while it's subscribed, so it can't miss anything after it
+        server.failed("line 1\nline 2");
+        assert_eq!(next_event(), "event: failure\ndata: \"line 1\\nline 2\"\n");
+        server.succeeded(served("two"));
+        assert_eq!(next_event(), "event: success\ndata: {}\n");
+    }
+}
@@ -25,7 +25,15 @@ fn test_bundle_snapshots() {
     let options = InputOptions::default();
     let output = "examples/one-file/bumble.js";
     let map_output = SourceMapOutput::Suppressed;
-    let _ = bundle(&entry_point, options, &output, &map_output).unwrap();
+    let _ = bundle(
+        &entry_point,
+        options,
+        &output,
+        &map_output,
+        &Default::default(),
+        num_cpus::get(),
+    )
+    .unwrap();
     assert_snapshot!(
         "one-file bundle",
         std::fs::read_to_string("examples/one-file/bumble.js")
@@ -34,6 +42,624 @@ fn test_bundle_snapshots() {
     );
 }
 
+#[test]
+fn test_bundle_package_type() {
+    let entry_point = Path::new("fixtures/module-type/index.js");
+    let output = tempfile::NamedTempFile::new().unwrap();
+    let modules = bundle(
+        entry_point,
+        InputOptions::default(),
+        output.path().to_str().unwrap(),
+        &SourceMapOutput::Suppressed,
+        &Default::default(),
+        num_cpus::get(),
+    )
+    .unwrap();
+    let deps = |path: &str| {
+        let mut deps = modules[Path::new(path)]
+            .deps
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        deps.sort();
+        deps
+    };
+    let is_es_module = |path: &str| {
+        modules[Path::new(path)]
+            .source
+            .prefix
+            .contains("__esModule")
+    };
+
+    // "type": "module" makes .js strict ESM, so require() is not a dependency
+    assert_eq!(deps("node_modules/esm-pkg/index.js"), vec!["./dep.js"]);
+    assert!(is_es_module("node_modules/esm-pkg/index.js"));
+    assert!(is_es_module("node_modules/esm-pkg/dep.js"));
+
+    // .cjs is always CommonJS, and is tried as an extension
+    assert_eq!(deps("node_modules/esm-pkg/legacy.cjs"), vec!["./helper"]);
+    assert!(!is_es_module("node_modules/esm-pkg/legacy.cjs"));
+    assert!(!is_es_module("node_modules/esm-pkg/helper.cjs"));
+    assert_eq!(deps("plain.cjs"), vec!["./helper"]);
+    assert!(!is_es_module("plain.cjs"));
+    assert!(!is_es_module("index.js"));
+}
+
+// an index.js requiring `count` packages in node_modules, each of which requires the next
+fn write_package_tree(root: &Path, count: usize) {
+    let mut index = String::new();
+    for i in 0..count {
+        index.push_str(&format!("require('pkg{}')\n", i));
+        let dir = root.join(format!("node_modules/pkg{}", i));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("package.json"), r#"{"main": "lib/main.js"}"#).unwrap();
+        fs::write(
+            dir.join("lib/main.js"),
+            format!("require('pkg{}')\nrequire('./util')\n", (i + 1) % count),
+        )
+        .unwrap();
+        fs::write(dir.join("lib/util.js"), "").unwrap();
+    }
+    fs::write(root.join("index.js"), index).unwrap();
+}
+
+// write_package_tree in a new temporary directory, which lasts as long as the TempDir; returns
+// it, its canonical path, the entry point and where to write the bundle
+fn package_tree_dir(count: usize) -> (tempfile::TempDir, PathBuf, PathBuf, PathBuf) {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().canonicalize().unwrap();
+    write_package_tree(&root, count);
+    let entry_point = root.join("index.js");
+    let output = root.join("bundle.js");
+    (dir, root, entry_point, output)
+}
+
+// a bundler with a package cache of its own, as in watch mode
+fn tree_bundler<'a>(
+    entry_point: &Path,
+    output: &'a Path,
+    map_output: &'a SourceMapOutput<'a>,
+) -> Bundler<'a> {
+    Bundler::new(
+        entry_point,
+        InputOptions::default(),
+        output.to_str().unwrap(),
+        map_output,
+        Arc::new(PackageCache::default()),
+        4,
+    )
+}
+
+#[test]
+fn test_bundle_shares_package_cache() {
+    let dir = tempfile::tempdir().unwrap();
+    write_package_tree(dir.path(), 50);
+    let entry_point = dir.path().join("index.js");
+    let output = tempfile::NamedTempFile::new().unwrap();
+    let cache = Arc::new(PackageCache::default());
+    let build = |jobs| {
+        bundle(
+            &entry_point,
+            InputOptions::default(),
+            output.path().to_str().unwrap(),
+            &SourceMapOutput::Suppressed,
+            &cache,
+            jobs,
+        )
+        .unwrap()
+    };
+
+    // each package.json is read once, by whichever worker needs it first
+    assert_eq!(build(8).len(), 101);
+    assert_eq!(cache.manifest_reads(), 50);
+    // and not again when rebuilding
+    assert_eq!(build(1).len(), 101);
+    assert_eq!(cache.manifest_reads(), 50);
+
+    // until it changes
+    let manifest = dir.path().join("node_modules/pkg7/package.json");
+    fs::write(&manifest, r#"{"main": "lib/util.js"}"#).unwrap();
+    cache.invalidate(&manifest);
+    let modules = build(8);
+    assert_eq!(cache.manifest_reads(), 51);
+    assert_eq!(modules.len(), 100);
+    assert!(!modules.contains_key(Path::new("node_modules/pkg7/lib/main.js")));
+}
+
+#[test]
+fn test_incremental_rebuild() {
+    let (_dir, root, entry_point, output) = package_tree_dir(5);
+    let map_output = SourceMapOutput::Suppressed;
+    let mut bundler = tree_bundler(&entry_point, &output, &map_output);
+    let stats = |modules, included, resolved| BuildStats {
+        modules,
+        included,
+        resolved,
+    };
+    let pkg = |path: &str| root.join("node_modules").join(path);
+
+    assert_eq!(bundler.build(&[]).unwrap(), stats(11, 11, 15));
+    assert_eq!(bundler.build(&[]).unwrap(), stats(11, 0, 0));
+
+    // an edited module is read again, but what it requires resolves as before
+    fs::write(pkg("pkg2/lib/util.js"), "exports.edited = true\n").unwrap();
+    assert_eq!(
+        bundler.build(&[pkg("pkg2/lib/util.js")]).unwrap(),
+        stats(11, 1, 0)
+    );
+
+    // a file appearing where a require looked before changes what it resolves to
+    fs::write(pkg("pkg0/lib/util"), "").unwrap();
+    assert_eq!(
+        bundler.build(&[pkg("pkg0/lib/util")]).unwrap(),
+        stats(11, 1, 1)
+    );
+    assert!(bundler.modules().contains_key(&pkg("pkg0/lib/util")));
+    assert!(!bundler.modules().contains_key(&pkg("pkg0/lib/util.js")));
+
+    // so does a file disappearing; a failed build is picked up by the next one
+    fs::remove_file(pkg("pkg1/lib/util.js")).unwrap();
+    assert_matches!(
+        bundler.build(&[pkg("pkg1/lib/util.js")]),
+        Err(CliError::ModuleNotFound { .. })
+    );
+    fs::write(pkg("pkg1/lib/util.js"), "exports.back = true\n").unwrap();
+    assert_eq!(
+        bundler.build(&[pkg("pkg1/lib/util.js")]).unwrap(),
+        stats(11, 1, 0)
+    );
+
+    // and a manifest can change any of them
+    fs::write(pkg("pkg3/package.json"), r#"{"main": "lib/util.js"}"#).unwrap();
+    assert_eq!(
+        bundler.build(&[pkg("pkg3/package.json")]).unwrap(),
+        stats(10, 0, 13)
+    );
+
+    // the bundle is the same as one built from scratch
+    let incremental = fs::read_to_string(&output).unwrap();
+    let output = root.join("from-scratch.js");
+    bundle(
+        &entry_point,
+        InputOptions::default(),
+        output.to_str().unwrap(),
+        &map_output,
+        &Default::default(),
+        4,
+    )
+    .unwrap();
+    assert_eq!(incremental, fs::read_to_string(output).unwrap());
+}
+
+#[test]
+fn test_watched_after_error() {
+    let (_dir, root, entry_point, output) = package_tree_dir(2);
+    let map_output = SourceMapOutput::Suppressed;
+    let mut bundler = tree_bundler(&entry_point, &output, &map_output);
+    let missing = root.join("lib/missing.js");
+
+    // a failed build watches what the last one did, and the files it tried that don't exist yet
+    bundler.build(&[]).unwrap();
+    fs::write(&entry_point, "require('pkg0')\nrequire('./lib/missing')\n").unwrap();
+    assert_matches!(
+        bundler.build(std::slice::from_ref(&entry_point)),
+        Err(CliError::ModuleNotFound { .. })
+    );
+    let watched = bundler.watched();
+    assert!(watched.contains(&entry_point));
+    assert!(watched.contains(&root.join("node_modules/pkg1/lib/main.js")));
+    assert!(watched.contains(&root.join("node_modules/pkg0/package.json")));
+    assert!(watched.contains(&missing));
+    let targets = watch_targets(&watched);
+    assert!(targets.contains(&root));
+    assert!(!targets.contains(&root.join("lib")));
+    assert!(affects_build(&root.join("lib"), &watched));
+    assert!(!affects_build(&output, &watched));
+
+    // creating the file fixes the build, which still watches the files that would resolve ahead
+    // of it
+    fs::create_dir(root.join("lib")).unwrap();
+    fs::write(&missing, "").unwrap();
+    bundler.build(&[root.join("lib")]).unwrap();
+    let watched = bundler.watched();
+    assert!(watched.contains(&missing));
+    assert!(watched.contains(&root.join("lib/missing")));
+    assert!(watched.contains(&root.join("lib/missing.mjs")));
+    assert!(!watched.contains(&root.join("lib/missing.json")));
+    // and the manifests that would change it
+    assert!(watched.contains(&root.join("lib/package.json")));
+}
+
+#[test]
+fn test_watch_shadowing_file_appears() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().canonicalize().unwrap();
+    fs::write(root.join("index.js"), "require('./dep')\n").unwrap();
+    fs::write(root.join("dep.js"), "exports.js = true\n").unwrap();
+    let entry_point = root.join("index.js");
+    let output = root.join("bundle.js");
+    let map_output = SourceMapOutput::Suppressed;
+    let mut bundler = tree_bundler(&entry_point, &output, &map_output);
+    bundler.build(&[]).unwrap();
+    assert!(fs::read_to_string(&output).unwrap().contains("dep.js"));
+
+    // watched the way the watch loop does it
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = FileWatcher::Poll(Poller::new(tx, time::Duration::from_millis(10)));
+    let watched = Arc::new(RwLock::new(bundler.watched()));
+    for path in watcher.targets(&watched.read().unwrap()) {
+        watcher.watch(&path).unwrap();
+    }
+    let cancel = bundler.cancel_handle();
+    let (changes_tx, changes) = mpsc::channel();
+    {
+        let watched = Arc::clone(&watched);
+        let cancel = cancel.clone();
+        thread::spawn(move || forward_changes(rx, changes_tx, &watched, &cancel));
+    }
+
+    // a file that resolves ahead of the one in the bundle
+    let mjs = root.join("dep.mjs");
+    fs::write(&mjs, "export const mjs = true\n").unwrap();
+    let changed = changes
+        .recv_timeout(time::Duration::from_secs(5))
+        .expect("the new file was never reported")
+        .unwrap();
+    assert_eq!(changed, mjs);
+    cancel.reset();
+    bundler.build(&[changed]).unwrap();
+    let bundle = fs::read_to_string(&output).unwrap();
+    assert!(bundle.contains("dep.mjs"));
+    assert!(!bundle.contains("exports.js = true"));
+}
+
+#[test]
+fn test_cancelled_build() {
+    let (_dir, root, entry_point, output) = package_tree_dir(3);
+    let map_output = SourceMapOutput::Suppressed;
+    let mut bundler = tree_bundler(&entry_point, &output, &map_output);
+    let cancel = bundler.cancel_handle();
+    let util = root.join("node_modules/pkg1/lib/util.js");
+
+    // a cancelled build writes nothing
+    cancel.cancel();
+    assert_matches!(bundler.build(&[]), Err(CliError::Cancelled));
+    assert!(!output.exists());
+    cancel.reset();
+    bundler.build(&[]).unwrap();
+
+    // and the next build still picks up what changed before it
+    fs::write(&util, "exports.edited = true\n").unwrap();
+    cancel.cancel();
+    assert_matches!(
+        bundler.build(std::slice::from_ref(&util)),
+        Err(CliError::Cancelled)
+    );
+    assert!(!fs::read_to_string(&output).unwrap().contains("edited"));
+    cancel.reset();
+    bundler.build(&[]).unwrap();
+    assert!(fs::read_to_string(&output).unwrap().contains("edited"));
+}
+
+#[test]
+fn test_output_replaced_when_changed() {
+    let (_dir, root, entry_point, output) = package_tree_dir(2);
+    let map = root.join("bundle.js.map");
+    let map_output = SourceMapOutput::File(map.clone(), &output);
+    let mut bundler = tree_bundler(&entry_point, &output, &map_output);
+    let modified = |path: &Path| fs::metadata(path).unwrap().modified().unwrap();
+    let util = root.join("node_modules/pkg1/lib/util.js");
+
+    bundler.build(&[]).unwrap();
+    let (bundle_modified, map_modified) = (modified(&output), modified(&map));
+
+    // the same bundle isn't written again
+    thread::sleep(time::Duration::from_millis(20));
+    fs::write(&util, "").unwrap();
+    bundler.build(std::slice::from_ref(&util)).unwrap();
+    assert_eq!(modified(&output), bundle_modified);
+    assert_eq!(modified(&map), map_modified);
+
+    // a different one replaces it, leaving nothing else behind
+    fs::write(&util, "exports.edited = true\n").unwrap();
+    bundler.build(std::slice::from_ref(&util)).unwrap();
+    assert!(fs::read_to_string(&output).unwrap().contains("edited"));
+    assert_ne!(modified(&map), map_modified);
+    let mut files: Vec<_> = fs::read_dir(&root)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .filter(|name| name.to_string_lossy().contains("bundle"))
+        .collect();
+    files.sort();
+    assert_eq!(files, vec!["bundle.js", "bundle.js.map"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_output_through_symlink() {
+    use std::os::unix::fs::symlink;
+
+    let (_dir, root, entry_point, output) = package_tree_dir(2);
+    let target = root.join("target.js");
+    fs::write(&target, "").unwrap();
+    symlink(&target, &output).unwrap();
+    let map_output = SourceMapOutput::Suppressed;
+
+    // the file it points to is written, and the link is left alone
+    tree_bundler(&entry_point, &output, &map_output)
+        .build(&[])
+        .unwrap();
+    assert!(fs::symlink_metadata(&output)
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    assert!(fs::read_to_string(&target)
+        .unwrap()
+        .contains("Scrumple.files["));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_output_to_dev_null() {
+    use std::os::unix::fs::FileTypeExt;
+
+    let (_dir, _, entry_point, _) = package_tree_dir(2);
+    let output = Path::new("/dev/null");
+    let map_output = SourceMapOutput::Suppressed;
+    let mut bundler = tree_bundler(&entry_point, output, &map_output);
+
+    bundler.build(&[]).unwrap();
+    bundler.build(std::slice::from_ref(&entry_point)).unwrap();
+    assert!(fs::symlink_metadata(output)
+        .unwrap()
+        .file_type()
+        .is_char_device());
+}
+
+#[test]
+fn test_served_in_memory() {
+    let (_dir, root, entry_point, output) = package_tree_dir(2);
+    let map = root.join("bundle.js.map");
+    let map_output = SourceMapOutput::File(map.clone(), &output);
+    let bundler = |serve| {
+        let bundler = tree_bundler(&entry_point, &output, &map_output);
+        if serve {
+            bundler.serve()
+        } else {
+            bundler
+        }
+    };
+    // how far the first module's first line is from where the source map puts it
+    let map_offset = |bundle: &[u8], map: &[u8]| {
+        let first_line = str::from_utf8(bundle)
+            .unwrap()
+            .lines()
+            .position(|line| line.contains("Scrumple.files["))
+            .unwrap()
+            + 1;
+        let map: serde_json::Value = serde_json::from_slice(map).unwrap();
+        let mappings = map["mappings"].as_str().unwrap();
+        mappings.find(|c| c != ';').unwrap() as isize - first_line as isize
+    };
+
+    let mut served_bundler = bundler(true);
+    // before the first build succeeds, just the client, which can show why it didn't
+    let served = served_bundler.served().unwrap();
+    assert!(str::from_utf8(&served.bundle)
+        .unwrap()
+        .contains("/_scrumple/events"));
+    assert!(!str::from_utf8(&served.bundle)
+        .unwrap()
+        .contains("Scrumple.files["));
+    assert!(served.map.is_none());
+
+    served_bundler.build(&[]).unwrap();
+    assert!(!output.exists());
+    let served = served_bundler.served().unwrap();
+    assert!(str::from_utf8(&served.bundle)
+        .unwrap()
+        .contains("/_scrumple/events"));
+
+    // the client that reloads the page doesn't throw the source map out
+    bundler(false).build(&[]).unwrap();
+    assert_eq!(
+        map_offset(&served.bundle, served.map.as_ref().unwrap()),
+        map_offset(&fs::read(&output).unwrap(), &fs::read(&map).unwrap())
+    );
+}
+
+#[test]
+fn test_disk_cache() {
+    let (_dir, root, entry_point, _) = package_tree_dir(5);
+    fs::write(
+        root.join("esm.mjs"),
+        "import x from 'pkg0'\nexport default x\n",
+    )
+    .unwrap();
+    fs::write(root.join("data.json"), "{\"a\": 1}\n").unwrap();
+    let mut index = fs::read_to_string(&entry_point).unwrap();
+    index.push_str("require('./esm.mjs')\nrequire('./data.json')\n");
+    fs::write(&entry_point, index).unwrap();
+    let cache_dir = root.join("cache");
+    let pkg = |path: &str| root.join("node_modules").join(path);
+
+    // each build starts from nothing but what's on disk; returns the bundle, its source map
+    // and how many cache entries it used
+    let build = |name: &str, cached: bool| {
+        let output = root.join(name).join("bundle.js");
+        let map = root.join(name).join("bundle.js.map");
+        let map_output = SourceMapOutput::File(map.clone(), &output);
+        let mut bundler = Bundler::new(
+            &entry_point,
+            InputOptions::default(),
+            output.to_str().unwrap(),
+            &map_output,
+            Arc::new(PackageCache::default()),
+            4,
+        );
+        let disk_cache =
+            Arc::new(DiskCache::new(cache_dir.clone(), &InputOptions::default()).unwrap());
+        if cached {
+            bundler = bundler.with_disk_cache(Arc::clone(&disk_cache));
+        }
+        bundler.build(&[]).unwrap();
+        (
+            fs::read_to_string(&output).unwrap(),
+            fs::read_to_string(&map).unwrap(),
+            disk_cache.hits(),
+        )
+    };
+
+    let (plain, plain_map, _) = build("plain", false);
+    let (cold, cold_map, _) = build("cold", true);
+    // 13 modules and 18 requires
+    let (warm, warm_map, hits) = build("warm", true);
+    assert_eq!(hits, 31);
+    assert_eq!(cold, plain);
+    assert_eq!(warm, plain);
+    assert_eq!(cold_map, plain_map);
+    assert_eq!(warm_map, plain_map);
+
+    // an edited module misses, but what everything requires resolves as before
+    fs::write(pkg("pkg2/lib/util.js"), "exports.edited = true\n").unwrap();
+    let (warm, warm_map, hits) = build("warm", true);
+    assert_eq!(hits, 30);
+    let (plain, plain_map, _) = build("plain", false);
+    assert_eq!(warm, plain);
+    assert_eq!(warm_map, plain_map);
+
+    // resolutions that read a changed manifest miss
+    fs::write(pkg("pkg3/package.json"), r#"{"main": "lib/util.js"}"#).unwrap();
+    let (warm, warm_map, _) = build("warm", true);
+    let (plain, plain_map, _) = build("plain", false);
+    assert_eq!(warm, plain);
+    assert_eq!(warm_map, plain_map);
+    assert!(!warm.contains("pkg3/lib/main.js"));
+}
+
+#[test]
+fn test_disk_cache_manifest_appears() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().canonicalize().unwrap();
+    fs::create_dir(root.join("lib")).unwrap();
+    fs::write(root.join("index.js"), "require('./lib')\n").unwrap();
+    fs::write(root.join("lib/index.js"), "exports.index = true\n").unwrap();
+    fs::write(root.join("lib/main.js"), "exports.main = true\n").unwrap();
+    let entry_point = root.join("index.js");
+    let output = root.join("bundle.js");
+    let cache_dir = root.join("cache");
+    let build = |cached: bool| {
+        let map_output = SourceMapOutput::Suppressed;
+        let mut bundler = tree_bundler(&entry_point, &output, &map_output);
+        if cached {
+            bundler = bundler.with_disk_cache(Arc::new(
+                DiskCache::new(cache_dir.clone(), &InputOptions::default()).unwrap(),
+            ));
+        }
+        bundler.build(&[]).unwrap();
+        fs::read_to_string(&output).unwrap()
+    };
+
+    assert!(build(true).contains("lib/index.js"));
+    // a resolution also depends on the manifests that weren't there
+    fs::write(root.join("lib/package.json"), r#"{"main": "main.js"}"#).unwrap();
+    let warm = build(true);
+    assert!(warm.contains("lib/main.js"));
+    assert!(!warm.contains("lib/index.js"));
+    assert_eq!(warm, build(false));
+}
+
+#[test]
+fn test_disk_cache_workspace_renamed() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().canonicalize().unwrap();
+    for package in &["app", "a", "b"] {
+        fs::create_dir_all(root.join("packages").join(package)).unwrap();
+    }
+    fs::write(
+        root.join("package.json"),
+        r#"{"workspaces": ["packages/*"]}"#,
+    )
+    .unwrap();
+    fs::write(root.join("packages/app/index.js"), "require('lib')\n").unwrap();
+    fs::write(root.join("packages/a/package.json"), r#"{"name": "other"}"#).unwrap();
+    fs::write(root.join("packages/a/index.js"), "exports.a = true\n").unwrap();
+    fs::write(root.join("packages/b/package.json"), r#"{"name": "lib"}"#).unwrap();
+    fs::write(root.join("packages/b/index.js"), "exports.b = true\n").unwrap();
+    let entry_point = root.join("packages/app/index.js");
+    let output = root.join("bundle.js");
+    let cache_dir = root.join("cache");
+    let build = |cached: bool| {
+        let map_output = SourceMapOutput::Suppressed;
+        let mut bundler = tree_bundler(&entry_point, &output, &map_output);
+        if cached {
+            bundler = bundler.with_disk_cache(Arc::new(
+                DiskCache::new(cache_dir.clone(), &InputOptions::default()).unwrap(),
+            ));
+        }
+        bundler.build(&[]).unwrap();
+        fs::read_to_string(&output).unwrap()
+    };
+
+    assert!(build(true).contains("exports.b"));
+    // the resolution depends on the names of all the workspace packages, and the first one
+    // named "lib" wins
+    fs::write(root.join("packages/a/package.json"), r#"{"name": "lib"}"#).unwrap();
+    let warm = build(true);
+    assert!(warm.contains("exports.a"));
+    assert!(!warm.contains("exports.b"));
+    assert_eq!(warm, build(false));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_bundle_symlinks() {
+    use std::os::unix::fs::symlink;
+
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join("index.js"),
+        "require('./a')\nrequire('./b')\n",
+    )
+    .unwrap();
+    fs::write(dir.path().join("a.js"), "").unwrap();
+    symlink("a.js", dir.path().join("b.js")).unwrap();
+
+    let entry_point = dir.path().join("index.js");
+    let output = tempfile::NamedTempFile::new().unwrap();
+    let bundle_paths = |input_options| {
+        let modules = bundle(
+            &entry_point,
+            input_options,
+            output.path().to_str().unwrap(),
+            &SourceMapOutput::Suppressed,
+            &Default::default(),
+            num_cpus::get(),
+        )
+        .unwrap();
+        let mut paths = modules.keys().cloned().collect::<Vec<_>>();
+        paths.sort();
+        paths
+    };
+
+    // a.js is included once, under its real path
+    assert_eq!(
+        bundle_paths(InputOptions::default()),
+        vec![PathBuf::from("a.js"), PathBuf::from("index.js")]
+    );
+    assert_eq!(
+        bundle_paths(InputOptions {
+            preserve_symlinks: true,
+            ..Default::default()
+        }),
+        vec![
+            PathBuf::from("a.js"),
+            PathBuf::from("b.js"),
+            PathBuf::from("index.js")
+        ]
+    );
+}
+
 #[test]
 fn test_count_lines() {
     assert_eq!(count_lines(""), 1);
@@ -79,6 +705,33 @@ fn test_gather_npm_dev_deps_finds_complicated_deps() {
     );
 }
 
+#[test]
+fn test_gather_npm_dev_deps_finds_scoped_deps() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    let write = |path: &str, contents: &str| {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    };
+    write("index.js", "");
+    write(
+        "package.json",
+        r#"{"devDependencies": {"@scope/dev": "1.0.0"}}"#,
+    );
+    write(
+        "node_modules/@scope/dev/package.json",
+        r#"{"dependencies": {"plain": "1.0.0"}}"#,
+    );
+    write("node_modules/plain/package.json", "{}");
+
+    let found = gather_npm_dev_deps(root.join("index.js").to_str().unwrap()).unwrap();
+    let mut expected = FnvHashSet::default();
+    expected.insert("@scope/dev".to_owned());
+    expected.insert("plain".to_owned());
+    assert_eq!(found, expected);
+}
+
 #[test]
 fn test_gather_npm_dev_deps_doesnt_fail_on_missing_optionals() {
     assert!(
@@ -121,7 +774,7 @@ cfg_if! {
             let map_output = SourceMapOutput::Inline;
 
             b.iter(|| {
-                let _ = bundle(&entry_point, options, &output, &map_output).unwrap();
+                let _ = bundle(&entry_point, options, &output, &map_output, &Default::default(), num_cpus::get()).unwrap();
             });
         }
 
@@ -134,7 +787,7 @@ cfg_if! {
             let map_output = SourceMapOutput::Inline;
 
             b.iter(|| {
-                let _ = bundle(&entry_point, options, &output, &map_output).unwrap();
+                let _ = bundle(&entry_point, options, &output, &map_output, &Default::default(), num_cpus::get()).unwrap();
             });
         }
 
@@ -147,8 +800,38 @@ cfg_if! {
             let map_output = SourceMapOutput::Inline;
 
             b.iter(|| {
-                let _ = bundle(&entry_point, options, &output, &map_output).unwrap();
+                let _ = bundle(&entry_point, options, &output, &map_output, &Default::default(), num_cpus::get()).unwrap();
+            });
+        }
+
+        // compare the manifest reads printed by the two: every build with a fresh cache reads each
+        // package.json once, and rebuilds with a shared one don't read any
+        #[bench]
+        fn bench_bundle_large_node_modules(b: &mut test::Bencher) {
+            let dir = tempfile::tempdir().unwrap();
+            write_package_tree(dir.path(), 1000);
+            let entry_point = dir.path().join("index.js");
+            let mut reads = 0;
+            b.iter(|| {
+                let cache = Arc::new(PackageCache::default());
+                let _ = bundle(&entry_point, InputOptions::default(), "/dev/null", &SourceMapOutput::Suppressed, &cache, num_cpus::get()).unwrap();
+                reads = cache.manifest_reads();
+            });
+            eprintln!("manifest reads per build: {}", reads);
+        }
+
+        #[bench]
+        fn bench_rebuild_large_node_modules(b: &mut test::Bencher) {
+            let dir = tempfile::tempdir().unwrap();
+            write_package_tree(dir.path(), 1000);
+            let entry_point = dir.path().join("index.js");
+            let cache = Arc::new(PackageCache::default());
+            let _ = bundle(&entry_point, InputOptions::default(), "/dev/null", &SourceMapOutput::Suppressed, &cache, num_cpus::get()).unwrap();
+            let first_build = cache.manifest_reads();
+            b.iter(|| {
+                let _ = bundle(&entry_point, InputOptions::default(), "/dev/null", &SourceMapOutput::Suppressed, &cache, num_cpus::get()).unwrap();
             });
+            eprintln!("manifest reads per rebuild: {}", cache.manifest_reads() - first_build);
         }
 
         #[bench]
@@ -186,6 +869,7 @@ cfg_if! {
                 },
                 entry_point: Path::new("examples/es6-everywhere-simple/index.js"),
                 map_output: &SourceMapOutput::Inline,
+                live_reload: false,
             };
 
             let mut out = Vec::new();
@@ -1,19 +1,23 @@
-use crate::es6;
+use crate::disk_cache::DiskCache;
+use crate::es6::{self, ModuleKind};
 use crate::input_options::InputOptions;
+use crate::manifest::{PackageCache, PackageType};
 use crate::modules::{self, ModuleInfo};
-use crate::resolver::{Resolved, Resolver};
+use crate::resolver::{Outcome, Resolved, Resolver, TraceStep};
 use crate::CliError;
-use crossbeam::queue::SegQueue;
+use crossbeam::deque::{self, Injector, Steal, Stealer};
 use esparse::lex;
 use fnv::FnvHashSet;
 use matches::matches;
+use std::any::Any;
 use std::io::{self, Read};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
-use std::sync::Arc;
-use std::thread;
+use std::sync::{Arc, Condvar, Mutex};
+use std::{fmt, iter};
 
 #[derive(Debug)]
 pub enum Work {
@@ -27,6 +31,8 @@ pub enum WorkDone {
         context: PathBuf,
         name: String,
         resolved: Resolved,
+        // the files tried, and whether they existed
+        inputs: Vec<(PathBuf, bool)>,
     },
     Include {
         module: PathBuf,
@@ -34,20 +40,107 @@ pub enum WorkDone {
     },
 }
 
+impl fmt::Display for Work {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Work::Resolve {
+                ref context,
+                ref name,
+            } => write!(f, "resolving '{}' in {}", name, context.display()),
+            Work::Include { ref module } => write!(f, "including {}", module.display()),
+        }
+    }
+}
+
+// the work of a build, shared by its workers: each takes work from the queue in batches into its
+// own deque, steals from the others' deques once the queue is empty, and sleeps when there's
+// nothing left to take until more work is added or the build is over
+#[derive(Debug)]
+pub struct WorkQueue {
+    injector: Injector<Work>,
+    stealers: Vec<Stealer<Work>>,
+    quit: AtomicBool,
+    sleep: Mutex<()>,
+    wake: Condvar,
+}
+
+impl WorkQueue {
+    // the queue, and a deque for each of its `jobs` workers
+    pub fn new(jobs: usize) -> (WorkQueue, Vec<deque::Worker<Work>>) {
+        let deques: Vec<_> = (0..jobs).map(|_| deque::Worker::new_fifo()).collect();
+        let queue = WorkQueue {
+            injector: Injector::new(),
+            stealers: deques.iter().map(deque::Worker::stealer).collect(),
+            quit: AtomicBool::new(false),
+            sleep: Mutex::new(()),
+            wake: Condvar::new(),
+        };
+        (queue, deques)
+    }
+
+    pub fn push(&self, work: Work) {
+        self.injector.push(work);
+        // a worker that just found the queue empty holds the lock until it's waiting
+        let _sleep = self.sleep.lock().unwrap();
+        self.wake.notify_one();
+    }
+
+    // stops the workers, leaving any work that's left
+    pub fn quit(&self) {
+        self.quit.store(true, Ordering::SeqCst);
+        let _sleep = self.sleep.lock().unwrap();
+        self.wake.notify_all();
+    }
+
+    fn is_empty(&self) -> bool {
+        self.injector.is_empty() && self.stealers.iter().all(Stealer::is_empty)
+    }
+
+    fn find(&self, local: &deque::Worker<Work>) -> Option<Work> {
+        local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.injector
+                    .steal_batch_and_pop(local)
+                    .or_else(|| self.stealers.iter().map(Stealer::steal).collect())
+            })
+            .find(|steal| !steal.is_retry())
+            .and_then(Steal::success)
+        })
+    }
+
+    // blocks until there's work for the worker with the deque `local`, or the build is over
+    fn next(&self, local: &deque::Worker<Work>) -> Option<Work> {
+        loop {
+            if self.quit.load(Ordering::SeqCst) {
+                return None;
+            }
+            if let Some(work) = self.find(local) {
+                return Some(work);
+            }
+            let sleep = self.sleep.lock().unwrap();
+            if !self.quit.load(Ordering::SeqCst) && self.is_empty() {
+                drop(self.wake.wait(sleep).unwrap());
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct WorkerInit {
     pub tx: mpsc::Sender<Result<WorkDone, CliError>>,
     pub input_options: InputOptions,
-    pub queue: Arc<SegQueue<Work>>,
-    pub quit: Arc<AtomicBool>,
+    pub cache: Arc<PackageCache>,
+    pub disk_cache: Option<Arc<DiskCache>>,
+    pub queue: Arc<WorkQueue>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Worker {
     tx: mpsc::Sender<Result<WorkDone, CliError>>,
     pub resolver: Resolver,
-    queue: Arc<SegQueue<Work>>,
-    quit: Arc<AtomicBool>,
+    disk_cache: Option<Arc<DiskCache>>,
+    queue: Arc<WorkQueue>,
+    local: deque::Worker<Work>,
 }
 
 impl WorkerInit {
@@ -57,30 +150,26 @@ impl WorkerInit {
 }
 
 impl Worker {
-    pub fn new(init: WorkerInit) -> Self {
+    pub fn new(init: WorkerInit, local: deque::Worker<Work>) -> Self {
         Worker {
             tx: init.tx,
-            resolver: Resolver::new(init.input_options),
+            resolver: Resolver::with_cache(init.input_options, init.cache).with_trace(),
+            disk_cache: init.disk_cache,
             queue: init.queue,
-            quit: init.quit,
+            local,
         }
     }
 
-    pub fn run(mut self) {
-        while let Some(work) = self.get_work() {
-            let work_done = match work {
-                Work::Resolve { context, name } => {
-                    self.resolver
-                        .resolve(&context, &name)
-                        .map(|resolved| WorkDone::Resolve {
-                            context,
-                            name,
-                            resolved,
-                        })
+    pub fn run(self) {
+        while let Some(work) = self.queue.next(&self.local) {
+            let doing = work.to_string();
+            let work_done = match panic::catch_unwind(AssertUnwindSafe(|| self.work(work))) {
+                Ok(work_done) => work_done,
+                Err(payload) => {
+                    // the worker's state can't be trusted anymore
+                    let _ = self.tx.send(Err(panicked(Some(doing), payload)));
+                    return;
                 }
-                Work::Include { module } => self
-                    .include(&module)
-                    .map(|info| WorkDone::Include { module, info }),
             };
             if self.tx.send(work_done).is_err() {
                 return;
@@ -88,6 +177,60 @@ impl Worker {
         }
     }
 
+    fn work(&self, work: Work) -> Result<WorkDone, CliError> {
+        match work {
+            Work::Resolve { context, name } => {
+                if let Some((resolved, inputs)) = self
+                    .disk_cache
+                    .as_ref()
+                    .and_then(|disk_cache| disk_cache.resolution(&context, &name))
+                {
+                    return Ok(WorkDone::Resolve {
+                        context,
+                        name,
+                        resolved,
+                        inputs,
+                    });
+                }
+                let resolved = self.resolver.resolve(&context, &name);
+                let trace = self.resolver.take_trace();
+                let resolved = resolved?;
+                let mut inputs = Vec::new();
+                let mut manifests = Vec::new();
+                for step in trace {
+                    match step {
+                        TraceStep::Candidate {
+                            path,
+                            outcome: Outcome::Missing,
+                        } => inputs.push((path, false)),
+                        TraceStep::Candidate {
+                            path,
+                            outcome: Outcome::Found,
+                        } => inputs.push((path, true)),
+                        TraceStep::Manifest { path, .. } => manifests.push(path),
+                        TraceStep::NoManifest { path } => {
+                            inputs.push((path.clone(), false));
+                            manifests.push(path);
+                        }
+                        _ => {}
+                    }
+                }
+                if let Some(ref disk_cache) = self.disk_cache {
+                    disk_cache.store_resolution(&context, &name, &resolved, &inputs, &manifests);
+                }
+                Ok(WorkDone::Resolve {
+                    context,
+                    name,
+                    resolved,
+                    inputs,
+                })
+            }
+            Work::Include { module } => self
+                .include(&module)
+                .map(|info| WorkDone::Include { module, info }),
+        }
+    }
+
     fn include(&self, module: &Path) -> Result<ModuleInfo, CliError> {
         let source = {
             let file = std::fs::File::open(module)?;
@@ -104,6 +247,41 @@ impl Worker {
                 }
             }
         };
+
+        // None for JSON
+        let ext = module.extension();
+        let kind = if matches!(ext, Some(s) if s == "json") {
+            None
+        } else if matches!(ext, Some(s) if s == "mjs") {
+            Some(ModuleKind::Esm)
+        } else if matches!(ext, Some(s) if s == "cjs") {
+            Some(ModuleKind::CommonJs)
+        } else if matches!(ext, Some(s) if s == "js")
+            && self.resolver.package_type(module)? == PackageType::Module
+        {
+            Some(ModuleKind::Esm)
+        } else {
+            Some(ModuleKind::Detect)
+        };
+
+        let disk_cache = match self.disk_cache {
+            Some(ref disk_cache) => disk_cache,
+            None => return Self::transform(module, source, kind),
+        };
+        let key = DiskCache::module_key(kind, &source);
+        if let Some(info) = disk_cache.module(&key, &source) {
+            return Ok(info);
+        }
+        let info = Self::transform(module, source, kind)?;
+        disk_cache.store_module(&key, &info);
+        Ok(info)
+    }
+
+    fn transform(
+        module: &Path,
+        source: String,
+        kind: Option<ModuleKind>,
+    ) -> Result<ModuleInfo, CliError> {
         let mut new_source = None;
         let prefix;
         let suffix;
@@ -114,24 +292,20 @@ impl Worker {
             let mut lexer = lex::Lexer::new(path_string.as_ref(), &source);
 
             let deps;
-            let ext = module.extension();
-            if matches!(ext, Some(s) if s == "mjs") {
-                let module = es6::module_to_cjs(&mut lexer, false)?;
-                // println!("{:#?}", module);
-                deps = module.deps;
-                prefix = module.source_prefix;
-                suffix = module.source_suffix;
-                new_source = Some(module.source);
-            } else if matches!(ext, Some(s) if s == "json") {
-                deps = FnvHashSet::default();
-                prefix = "module.exports =".to_owned();
-                suffix = String::new();
-            } else {
-                let module = es6::module_to_cjs(&mut lexer, true)?;
-                deps = module.deps;
-                prefix = module.source_prefix;
-                suffix = module.source_suffix;
-                new_source = Some(module.source);
+            match kind {
+                None => {
+                    deps = FnvHashSet::default();
+                    prefix = "module.exports =".to_owned();
+                    suffix = String::new();
+                }
+                Some(kind) => {
+                    let module = es6::module_to_cjs(&mut lexer, kind)?;
+                    // println!("{:#?}", module);
+                    deps = module.deps;
+                    prefix = module.source_prefix;
+                    suffix = module.source_suffix;
+                    new_source = Some(module.source);
+                }
             }
 
             if let Some(error) = lexer.take_error() {
@@ -150,7 +324,7 @@ impl Worker {
         }
 
         Ok(ModuleInfo {
-            source: match new_source {
+            source: Arc::new(match new_source {
                 None => modules::Source {
                     prefix,
                     body: source,
@@ -163,24 +337,85 @@ impl Worker {
                     suffix,
                     original: Some(source),
                 },
-            },
+            }),
             deps,
         })
     }
+}
 
-    fn get_work(&mut self) -> Option<Work> {
-        loop {
-            match self.queue.pop() {
-                Some(work) => return Some(work),
-                None => {
-                    if self.quit.load(Ordering::Relaxed) {
-                        return None;
-                    } else {
-                        thread::yield_now();
-                        // thread::sleep(time::Duration::from_millis(1));
+// the error for a panic in a worker thread, which was `doing` something if it's known
+pub fn panicked(doing: Option<String>, payload: Box<dyn Any + Send>) -> CliError {
+    let message = match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => (*message).to_owned(),
+            Err(_) => "unknown panic".to_owned(),
+        },
+    };
+    CliError::WorkerPanic { doing, message }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_work_queue() {
+        let (queue, deques) = WorkQueue::new(4);
+        let queue = Arc::new(queue);
+        let (tx, rx) = mpsc::channel();
+        let workers: Vec<_> = deques
+            .into_iter()
+            .map(|local| {
+                let queue = Arc::clone(&queue);
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    while let Some(work) = queue.next(&local) {
+                        tx.send(work).unwrap();
                     }
+                })
+            })
+            .collect();
+
+        // the workers are asleep by now, and wake up for each batch
+        for batch in 0..3 {
+            for i in 0..100 {
+                queue.push(Work::Include {
+                    module: PathBuf::from(format!("{}/{}.js", batch, i)),
+                });
+            }
+            let done = rx.iter().take(100).collect::<Vec<_>>();
+            assert_eq!(done.len(), 100);
+            for work in done {
+                match work {
+                    Work::Include { module } => assert!(module.starts_with(batch.to_string())),
+                    other => panic!("expected Include, got {:?}", other),
                 }
             }
         }
+
+        queue.quit();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_panicked() {
+        let payload = panic::catch_unwind(|| panic!("at {}", "the disco")).unwrap_err();
+        match panicked(Some("including a.js".to_owned()), payload) {
+            CliError::WorkerPanic { doing, message } => {
+                assert_eq!(doing.as_deref(), Some("including a.js"));
+                assert_eq!(message, "at the disco");
+            }
+            other => panic!("expected WorkerPanic, got {:?}", other),
+        }
+        let payload = panic::catch_unwind(|| panic!("static")).unwrap_err();
+        assert_eq!(
+            panicked(None, payload).to_string(),
+            "internal error in a worker thread: static"
+        );
     }
 }
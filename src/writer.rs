@@ -14,12 +14,15 @@ use std::path::{Path, PathBuf};
 
 const HEAD_JS: &str = include_str!("javascript/head.js");
 const TAIL_JS: &str = include_str!("javascript/tail.js");
+// for `scrumple serve`: reloads the page after each rebuild, and shows build errors over it
+pub const LIVE_RELOAD_JS: &str = include_str!("javascript/live_reload.js");
 
 #[derive(Debug)]
 pub struct Writer<'a, 'b> {
     pub modules: FnvHashMap<PathBuf, Module>,
     pub entry_point: &'a Path,
     pub map_output: &'b SourceMapOutput<'b>,
+    pub live_reload: bool,
 }
 
 impl<'a, 'b> Writer<'a, 'b> {
@@ -33,8 +36,20 @@ impl<'a, 'b> Writer<'a, 'b> {
         modules
     }
 
+    // the lines before the first module
+    fn head_lines(&self) -> usize {
+        if self.live_reload {
+            count_lines(HEAD_JS) + count_lines(LIVE_RELOAD_JS) - 1
+        } else {
+            count_lines(HEAD_JS)
+        }
+    }
+
     pub fn write_to<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
         w.write_all(HEAD_JS.as_bytes())?;
+        if self.live_reload {
+            w.write_all(LIVE_RELOAD_JS.as_bytes())?;
+        }
 
         // TODO understand what this was
         // for (module, main) in self.mains {
@@ -177,6 +192,7 @@ impl<'a, 'b> Writer<'a, 'b> {
         #[derive(Debug)]
         struct Mappings<'a> {
             modules: &'a [(&'a Path, &'a Module)],
+            head_lines: usize,
         }
 
         impl<'a> Serialize for Mappings<'a> {
@@ -187,7 +203,7 @@ impl<'a, 'b> Writer<'a, 'b> {
 
         impl<'a> Display for Mappings<'a> {
             fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
-                let prefix_len = count_lines(HEAD_JS); /*+ this.mains.size*/
+                let prefix_len = self.head_lines; /*+ this.mains.size*/
                 for _ in 0..prefix_len {
                     w.write_str(";")?;
                 }
@@ -258,7 +274,10 @@ impl<'a, 'b> Writer<'a, 'b> {
                 sources: Sources { modules, dir },
                 sources_content: SourcesContent { modules },
                 names: [],
-                mappings: Mappings { modules },
+                mappings: Mappings {
+                    modules,
+                    head_lines: self.head_lines(),
+                },
             },
         )
     }
@@ -266,6 +285,9 @@ impl<'a, 'b> Writer<'a, 'b> {
     fn stringify_deps(deps: &FnvHashMap<String, Resolved>, entry_point: PathBuf) -> String {
         let mut result = "{".to_owned();
         let mut comma = false;
+        // in a stable order, so a rebuild that reuses modules writes the same bundle
+        let mut deps = deps.iter().collect::<Vec<_>>();
+        deps.sort_by_key(|(name, _)| *name);
         for (name, resolved) in deps {
             match *resolved {
                 Resolved::External => {}